@@ -0,0 +1,276 @@
+//! Decide when to start another instance of mriqc based on the system's free
+//! memory, instead of always running a fixed number of instances.
+//!
+//! [`Admission::admit()`] waits until `/proc/meminfo` shows enough available
+//! memory for another instance, or at least a minimum without an estimate,
+//! `/proc/pressure/memory` shows that tasks aren't stalling on memory, and the
+//! system isn't pushing more memory out to swap.  An instance is always
+//! admitted when no others are running, so that work can progress however
+//! little memory the system has.
+
+use crate::resources::MemoryLimit;
+use std::time::{Duration, Instant};
+
+/// The memory statistics from `/proc/meminfo` that we care about, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemInfo {
+    /// Estimate of the memory available for starting new applications
+    /// without swapping.
+    pub mem_available: u64,
+    /// Total swap space.
+    pub swap_total: u64,
+    /// Unused swap space.
+    pub swap_free: u64,
+}
+impl MemInfo {
+    /// Read the current statistics, or `None` if `/proc/meminfo` can't be
+    /// read, e.g. on platforms other than Linux.  `/proc` is in memory, so
+    /// blocking on it is fine.
+    pub fn read() -> Option<Self> {
+        Self::parse(&std::fs::read_to_string("/proc/meminfo").ok()?)
+    }
+
+    // Parse the contents of `/proc/meminfo`, where sizes are in kB.
+    fn parse(meminfo: &str) -> Option<Self> {
+        let field = |name: &str| meminfo.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024);
+        Some(Self {
+            mem_available: field("MemAvailable")?,
+            swap_total: field("SwapTotal").unwrap_or(0),
+            swap_free: field("SwapFree").unwrap_or(0)
+        })
+    }
+}
+
+/// Read the share of the last 10 seconds, as a percentage, in which some
+/// tasks were stalled waiting for memory, see the kernel's pressure stall
+/// information.  Returns `None` if `/proc/pressure/memory` can't be read, e.g.
+/// on kernels older than 4.20.
+pub fn memory_pressure() -> Option<f64> {
+    parse_pressure(&std::fs::read_to_string("/proc/pressure/memory").ok()?)
+}
+
+// Parse the `some avg10` value out of the contents of `/proc/pressure/memory`.
+fn parse_pressure(pressure: &str) -> Option<f64> {
+    pressure.lines()
+        .find_map(|line| line.strip_prefix("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse().ok()
+}
+
+/// Options for [`Admission`].
+#[derive(Debug, Clone)]
+pub struct AdmissionOptions {
+    /// Memory expected to be used by each instance of mriqc.  Another
+    /// instance is only started when this much memory is available, on top
+    /// of this much for each instance started within `ramp_up` that may not
+    /// have claimed its memory yet.  Defaults to no estimate.
+    pub per_instance: Option<MemoryLimit>,
+    /// Don't start another instance while less than this much memory is
+    /// available, whether or not `per_instance` is given.  Defaults to 2G.
+    pub min_available: MemoryLimit,
+    /// Don't start another instance while tasks were stalled waiting for
+    /// memory more than this percentage of the time over the last 10
+    /// seconds.  Defaults to 10.
+    pub max_pressure: f64,
+    /// How long an instance of mriqc takes to reach its full memory use.
+    /// Defaults to one minute.
+    pub ramp_up: Duration,
+    /// How often to check the memory while holding back an instance.
+    /// Defaults to 5 seconds.
+    pub poll_interval: Duration,
+}
+impl Default for AdmissionOptions {
+    fn default() -> Self {
+        Self {
+            per_instance: None,
+            min_available: MemoryLimit::from_bytes(2 << 30),
+            max_pressure: 10.0,
+            ramp_up: Duration::from_secs(60),
+            poll_interval: Duration::from_secs(5)
+        }
+    }
+}
+
+/// Why [`Admission::admit()`] is holding back an instance of mriqc.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HoldBack {
+    /// Not enough memory is available.
+    LowMemory {
+        /// Available memory in bytes.
+        available: u64,
+        /// Memory needed to start another instance in bytes.
+        needed: u64
+    },
+    /// Tasks are stalling on memory.
+    Pressure {
+        /// Share of the last 10 seconds in which tasks were stalled, as a
+        /// percentage.
+        avg10: f64
+    },
+    /// The system is moving memory out to swap.
+    Swapping,
+}
+impl std::fmt::Display for HoldBack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let gib = |bytes: u64| bytes as f64 / (1u64 << 30) as f64;
+        match self {
+            HoldBack::LowMemory { available, needed } => write!(f, "{:.1}G of {:.1}G memory available", gib(*available), gib(*needed)),
+            HoldBack::Pressure { avg10 } => write!(f, "memory pressure {:.0}%", avg10),
+            HoldBack::Swapping => write!(f, "system is swapping")
+        }
+    }
+}
+
+/// Admits instances of mriqc one at a time when there is enough memory for
+/// them, see the [module documentation](self).  Bound the number of
+/// instances separately, e.g. with `buffer_unordered()`.
+#[derive(Debug)]
+pub struct Admission {
+    options: AdmissionOptions,
+    // Held while waiting for admission, so that instances are admitted one
+    // at a time in the order they asked.
+    queue: tokio::sync::Mutex<()>,
+    // Bookkeeping shared with the tickets.
+    state: std::sync::Mutex<State>
+}
+#[derive(Debug, Default)]
+struct State {
+    // When each running instance was admitted.
+    running: Vec<Instant>,
+    // When we last checked and how much swap was unused, if the system has
+    // swap.
+    swap_free: Option<(Instant, u64)>
+}
+impl Admission {
+    /// Create a new admission scheduler.
+    pub fn new(options: AdmissionOptions) -> Self {
+        Self {
+            options,
+            queue: tokio::sync::Mutex::new(()),
+            state: Default::default()
+        }
+    }
+
+    /// Wait until there is enough memory to start another instance of mriqc.
+    /// `waiting` is called with the reason each time the instance is held
+    /// back.  The instance counts as running until the returned ticket is
+    /// dropped.
+    pub async fn admit<W: FnMut(&HoldBack)>(&self, mut waiting: W) -> Ticket<'_> {
+        let _queue = self.queue.lock().await;
+        while let Err(reason) = self.check() {
+            waiting(&reason);
+            tokio::time::sleep(self.options.poll_interval).await;
+        }
+        let admitted = Instant::now();
+        self.state.lock().unwrap().running.push(admitted);
+        Ticket { admission: self, admitted }
+    }
+
+    // Check whether another instance can start now.
+    fn check(&self) -> Result<(), HoldBack> {
+        let mut state = self.state.lock().unwrap();
+        let meminfo = MemInfo::read();
+        // The system is swapping if more than 1% of swap was used up since
+        // the previous poll.  Older readings say nothing about what the
+        // system is doing now.  Record the swap even if we admit
+        // unconditionally.
+        let swapping = match (state.swap_free, meminfo) {
+            (Some((checked, before)), Some(now)) => checked.elapsed() <= 2 * self.options.poll_interval
+                && before.saturating_sub(now.swap_free) > now.swap_total / 100,
+            _ => false
+        };
+        state.swap_free = meminfo.filter(|meminfo| meminfo.swap_total > 0).map(|meminfo| (Instant::now(), meminfo.swap_free));
+        if state.running.is_empty() {
+            return Ok(());
+        }
+        if let Some(meminfo) = meminfo {
+            let ramping_up = state.running.iter().filter(|admitted| admitted.elapsed() < self.options.ramp_up).count() as u64;
+            let needed = self.options.per_instance
+                .map(|per_instance| per_instance.bytes().saturating_mul(1 + ramping_up))
+                .unwrap_or(0)
+                .max(self.options.min_available.bytes());
+            if meminfo.mem_available < needed {
+                return Err(HoldBack::LowMemory { available: meminfo.mem_available, needed });
+            }
+        }
+        if let Some(avg10) = memory_pressure().filter(|avg10| *avg10 > self.options.max_pressure) {
+            return Err(HoldBack::Pressure { avg10 });
+        }
+        match swapping {
+            true => Err(HoldBack::Swapping),
+            false => Ok(())
+        }
+    }
+}
+
+/// An instance of mriqc admitted by [`Admission::admit()`].  Drop it when the
+/// instance finishes.
+#[derive(Debug)]
+pub struct Ticket<'a> {
+    admission: &'a Admission,
+    admitted: Instant
+}
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock().unwrap();
+        if let Some(i) = state.running.iter().position(|admitted| *admitted == self.admitted) {
+            state.running.swap_remove(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc() {
+        let meminfo = "MemTotal:        6147400 kB\nMemFree:         3284972 kB\nMemAvailable:    5541616 kB\nSwapTotal:       1048576 kB\nSwapFree:         524288 kB\n";
+        assert_eq!(MemInfo::parse(meminfo), Some(MemInfo {
+            mem_available: 5541616 * 1024,
+            swap_total: 1 << 30,
+            swap_free: 1 << 29
+        }));
+        assert_eq!(MemInfo::parse("MemTotal: 1 kB\n"), None);
+        let pressure = "some avg10=12.50 avg60=3.00 avg300=0.50 total=123\nfull avg10=1.00 avg60=0.00 avg300=0.00 total=4\n";
+        assert_eq!(parse_pressure(pressure), Some(12.5));
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        // Nothing to test without /proc/meminfo.
+        if MemInfo::read().is_none() {
+            return;
+        }
+        // More memory than any machine has, so only the first instance is
+        // admitted.
+        let admission = Admission::new(AdmissionOptions {
+            per_instance: Some(MemoryLimit::from_bytes(u64::MAX / 2)),
+            poll_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let first = admission.admit(|_| panic!("first instance held back")).await;
+        let mut held_back = None;
+        let second = tokio::time::timeout(Duration::from_millis(100), admission.admit(|reason| held_back = Some(*reason))).await;
+        assert!(second.is_err());
+        assert!(matches!(held_back, Some(HoldBack::LowMemory { .. })));
+        // Once the first instance finishes the next is admitted.
+        drop(first);
+        admission.admit(|_| panic!("instance held back with none running")).await;
+
+        // Without an estimate, instances are still held back when less than
+        // the minimum is available.
+        let admission = Admission::new(AdmissionOptions {
+            min_available: MemoryLimit::from_bytes(u64::MAX),
+            poll_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        assert_eq!(admission.check(), Ok(()));
+        let _first = admission.admit(|_| panic!("first instance held back")).await;
+        assert!(matches!(admission.check(), Err(HoldBack::LowMemory { needed: u64::MAX, .. })));
+    }
+}
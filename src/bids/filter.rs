@@ -0,0 +1,247 @@
+//! Select a subset of a participant's files by their BIDS entities.
+
+use super::{discover_sessions, is_dir, list_dir, BidsEntities, BidsError, BidsPath, SessionLabel};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// Filter selecting scans by the values of their BIDS entities, e.g.
+/// `task=rest,run=1` selects `sub-01_task-rest_run-1_bold.nii.gz` but not
+/// `sub-01_task-rest_run-2_bold.nii.gz`.  A file matches the filter if, for
+/// every entity in the filter, the file has that entity with one of the
+/// allowed values.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntityFilter {
+    // Entity key and its allowed values.
+    entities: Vec<(String, Vec<String>)>
+}
+impl EntityFilter {
+    /// Create an empty filter, which matches every file.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow the given value for entity `key`.  A filter may allow several
+    /// values for the same key.
+    pub fn allow<S1: Into<String>, S2: Into<String>>(&mut self, key: S1, value: S2) {
+        let key = key.into();
+        let value = value.into();
+        match self.entities.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, values)) => if !values.contains(&value) {
+                values.push(value)
+            },
+            None => self.entities.push((key, vec![value]))
+        }
+    }
+
+    /// Add all the entities allowed by `other` to this filter.
+    pub fn extend(&mut self, other: EntityFilter) {
+        for (key, values) in other.entities {
+            for value in values {
+                self.allow(key.clone(), value);
+            }
+        }
+    }
+
+    /// Check if this filter is empty, i.e. matches every file.
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// Check if a BIDS filename such as `sub-01_task-rest_bold.nii.gz`
+    /// matches this filter.
+    pub fn matches(&self, name: &str) -> bool {
+        match name.parse::<BidsPath>() {
+            Ok(name) => self.matches_entities(name.entities()),
+            Err(_) => self.is_empty()
+        }
+    }
+
+    /// Check if a file with the given entities matches this filter.
+    pub fn matches_entities(&self, entities: &BidsEntities) -> bool {
+        self.entities.iter().all(|(key, values)|
+            entities.get(key).map(|v| values.iter().any(|value| value == v)).unwrap_or(false)
+        )
+    }
+}
+impl std::str::FromStr for EntityFilter {
+    type Err = String;
+    /// Parse a filter from a comma-separated list of `key=value` pairs, e.g.
+    /// `task=rest,run=1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut filter = Self::new();
+        for pair in s.split(',') {
+            match pair.split_once('=') {
+                Some((key, value)) if !key.is_empty() && !value.is_empty() => filter.allow(key, value),
+                _ => return Err(format!("expected key=value, got \"{}\"", pair))
+            }
+        }
+        Ok(filter)
+    }
+}
+
+// Extensions of files that accompany a scan rather than being scans
+// themselves.
+const SIDECAR_EXTENSIONS: [&str; 5] = [".json", ".bval", ".bvec", ".tsv", ".tsv.gz"];
+
+// A file in one of the participant's datatype directories.
+struct Candidate {
+    // Path relative to the participant's directory.
+    path: PathBuf,
+    // File name.
+    name: String,
+    // Parsed file name, if it is a valid BIDS filename.
+    bids_path: Option<BidsPath>,
+}
+impl Candidate {
+    fn new(path: PathBuf, name: String) -> Self {
+        let bids_path = name.parse().ok();
+        Self { path, name, bids_path }
+    }
+    fn is_sidecar(&self) -> bool {
+        match &self.bids_path {
+            Some(bids_path) => SIDECAR_EXTENSIONS.contains(&bids_path.extension()),
+            None => true
+        }
+    }
+}
+
+/// Select the files of the participant whose data is at `src`, e.g.
+/// `/bids/sub-01`, that match `filter`, optionally restricted to one
+/// `session`.  Along with matching scans, the selection includes:
+///
+/// * sidecars (e.g. `.json`, `.bval`, `_events.tsv`) that apply to the
+///   matching scans under the BIDS inheritance principle,
+/// * fieldmaps whose `IntendedFor` refers to a matching scan, or whose
+///   `B0FieldIdentifier` is a matching scan's `B0FieldSource`, and
+/// * files that are not in a datatype directory, e.g.
+///   `sub-01_sessions.tsv`.
+///
+/// Returns the sorted paths of the selected files relative to `src`.
+pub async fn select_files<P: AsRef<Path>>(src: P, filter: &EntityFilter, session: Option<&SessionLabel>) -> Result<Vec<PathBuf>, BidsError> {
+    let src = src.as_ref();
+    let mut selected = Vec::new();
+
+    // Directories, relative to src, that may contain datatype directories.
+    let mut roots = vec![PathBuf::new()];
+    match session {
+        Some(session) => roots.push(session.path()),
+        None => roots.extend(discover_sessions(src).await?.iter().map(SessionLabel::path))
+    }
+
+    // Gather files in datatype directories, and select files that are not.
+    let mut candidates = Vec::new();
+    let mut fmaps = Vec::new();
+    for root in &roots {
+        for path in list_dir(&src.join(root)).await? {
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue
+            };
+            let rel_path = root.join(&name);
+            match is_dir(&path).await {
+                false => selected.push(rel_path),
+                // Sessions are roots in their own right.
+                true if name.starts_with("ses-") => continue,
+                true => for file in list_dir(&path).await? {
+                    let name = match file.file_name().and_then(|name| name.to_str()) {
+                        Some(name) => name.to_string(),
+                        None => continue
+                    };
+                    let candidate = Candidate::new(rel_path.join(&name), name);
+                    match rel_path.file_name().map(|datatype| datatype == "fmap").unwrap_or(false) {
+                        true => fmaps.push(candidate),
+                        false => candidates.push(candidate)
+                    }
+                }
+            }
+        }
+    }
+
+    // Select the scans matching the filter, and the sidecars that go with them.
+    let scans: Vec<&Candidate> = candidates.iter()
+        .filter(|candidate| !candidate.is_sidecar() && filter.matches(&candidate.name))
+        .collect();
+    let sidecars: Vec<&Candidate> = candidates.iter()
+        .filter(|candidate| candidate.is_sidecar() && (filter.matches(&candidate.name) || scans.iter().any(|scan| applies_to(candidate, scan))))
+        .collect();
+
+    // Find the fieldmaps needed by the selected scans.
+    let mut b0_sources = HashSet::new();
+    for sidecar in &sidecars {
+        if sidecar.name.ends_with(".json") {
+            b0_sources.extend(json_strings(&src.join(&sidecar.path), "B0FieldSource").await?);
+        }
+    }
+    let mut fmap_entities = Vec::new();
+    for fmap in fmaps.iter().filter(|fmap| fmap.name.ends_with(".json")) {
+        let fmap_path = src.join(&fmap.path);
+        let intended_for = json_strings(&fmap_path, "IntendedFor").await?;
+        let identifiers = json_strings(&fmap_path, "B0FieldIdentifier").await?;
+        // IntendedFor paths are relative to the participant's directory, or
+        // else BIDS URIs such as bids::sub-01/func/sub-01_bold.nii.gz.
+        let needed = intended_for.iter().any(|target| scans.iter().any(|scan|
+            Path::new(target.rsplit("::").next().unwrap_or(target)).ends_with(&scan.path)
+        )) || identifiers.iter().any(|identifier| b0_sources.contains(identifier));
+        if let (true, Some(bids_path)) = (needed, &fmap.bids_path) {
+            fmap_entities.push(bids_path.entities());
+        }
+    }
+    // Select all files belonging to the needed fieldmaps, e.g. both the
+    // phasediff and magnitude images.
+    let fmaps = fmaps.iter().filter(|fmap| match &fmap.bids_path {
+        Some(bids_path) => fmap_entities.contains(&bids_path.entities()),
+        None => false
+    });
+
+    selected.extend(scans.into_iter().chain(sidecars).chain(fmaps).map(|candidate| candidate.path.clone()));
+    selected.sort();
+    Ok(selected)
+}
+
+// Check if `sidecar` applies to `scan` under the inheritance principle, i.e.
+// it has the same suffix and a subset of the scan's entities.
+fn applies_to(sidecar: &Candidate, scan: &Candidate) -> bool {
+    match (&sidecar.bids_path, &scan.bids_path) {
+        (Some(sidecar), Some(scan)) =>
+            sidecar.suffix() == scan.suffix() && sidecar.entities().is_subset(scan.entities()),
+        _ => false
+    }
+}
+
+// Read the string, or array of strings, stored under `key` in the JSON object
+// in the file at `path`.  Returns an empty vector if the file isn't a JSON
+// object or doesn't contain the key.
+async fn json_strings(path: &Path, key: &str) -> Result<Vec<String>, BidsError> {
+    let contents = tokio::fs::read(path).await.map_err(|source| BidsError::ReadFile {
+        path: path.into(),
+        source
+    })?;
+    let value: serde_json::Value = match serde_json::from_slice(&contents) {
+        Ok(value) => value,
+        Err(_) => return Ok(Vec::new())
+    };
+    Ok(match value.get(key) {
+        Some(serde_json::Value::String(s)) => vec![s.clone()],
+        Some(serde_json::Value::Array(values)) => values.iter().filter_map(|v| v.as_str().map(String::from)).collect(),
+        _ => Vec::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter: EntityFilter = "task=rest,run=1".parse().unwrap();
+        assert!(filter.matches("sub-01_task-rest_run-1_bold.nii.gz"));
+        assert!(!filter.matches("sub-01_task-rest_run-2_bold.nii.gz"));
+        assert!(!filter.matches("sub-01_task-rest_bold.nii.gz"));
+        assert!(!filter.matches("sub-01_T1w.nii.gz"));
+        let mut filter: EntityFilter = "task=rest".parse().unwrap();
+        filter.extend("task=nback".parse().unwrap());
+        assert!(filter.matches("sub-01_task-nback_bold.nii.gz"));
+        assert!(EntityFilter::new().matches("sub-01_T1w.nii.gz"));
+        assert!("task".parse::<EntityFilter>().is_err());
+    }
+}
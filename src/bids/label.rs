@@ -0,0 +1,157 @@
+//! Labels identifying BIDS participants and sessions.
+//!
+//! Labels are joined into filesystem paths, e.g. `sub-<label>`, so they are
+//! validated strictly when they are created.  A label that could escape the
+//! BIDS tree (e.g. `../other` or `a/b`) is rejected with
+//! [`BidsError::UnsafeLabel`] and any other label that is not purely
+//! alphanumeric is rejected with [`BidsError::InvalidLabel`].
+
+use super::BidsError;
+use std::path::PathBuf;
+
+/// Label identifying a BIDS participant, e.g. `01` for the participant whose
+/// data is in the directory `sub-01`.  A label can be created from either form,
+/// with or without the `sub-` prefix, and always stores the label without the
+/// prefix.  Labels must be non-empty and, per the BIDS specification, contain
+/// only letters and digits.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ParticipantLabel(String);
+impl ParticipantLabel {
+    /// Create a new participant label from a string such as `01` or `sub-01`.
+    pub fn new<S: AsRef<str>>(label: S) -> Result<Self, BidsError> {
+        Ok(Self(validate(label.as_ref(), "sub-", "participant")?))
+    }
+
+    /// Get the label without the `sub-` prefix, e.g. `01`.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Get the name of this participant's directory in a BIDS tree, e.g.
+    /// `sub-01`.
+    pub fn dir_name(&self) -> String {
+        format!("sub-{}", self.0)
+    }
+
+    /// Get the path of this participant's directory relative to the root of a
+    /// BIDS tree or an mriqc output directory, e.g. `sub-01`.
+    pub fn path(&self) -> PathBuf {
+        self.dir_name().into()
+    }
+}
+impl std::str::FromStr for ParticipantLabel {
+    type Err = BidsError;
+    fn from_str(label: &str) -> Result<Self, Self::Err> {
+        Self::new(label)
+    }
+}
+impl std::fmt::Display for ParticipantLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl AsRef<str> for ParticipantLabel {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Label identifying one of a participant's sessions, e.g. `1` for the
+/// session whose data is in the directory `sub-01/ses-1`.  Like a
+/// [`ParticipantLabel`], it may be created with or without the `ses-` prefix
+/// and must contain only letters and digits.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SessionLabel(String);
+impl SessionLabel {
+    /// Create a new session label from a string such as `1` or `ses-1`.
+    pub fn new<S: AsRef<str>>(label: S) -> Result<Self, BidsError> {
+        Ok(Self(validate(label.as_ref(), "ses-", "session")?))
+    }
+
+    /// Get the label without the `ses-` prefix, e.g. `1`.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Get the name of this session's directory within a participant's
+    /// directory, e.g. `ses-1`.
+    pub fn dir_name(&self) -> String {
+        format!("ses-{}", self.0)
+    }
+
+    /// Get the path of this session's directory relative to the participant's
+    /// directory, e.g. `ses-1`.
+    pub fn path(&self) -> PathBuf {
+        self.dir_name().into()
+    }
+}
+impl std::str::FromStr for SessionLabel {
+    type Err = BidsError;
+    fn from_str(label: &str) -> Result<Self, Self::Err> {
+        Self::new(label)
+    }
+}
+impl std::fmt::Display for SessionLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl AsRef<str> for SessionLabel {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+// Strip the optional `prefix` from `label` and validate what remains.  Returns
+// the label without its prefix.
+fn validate(label: &str, prefix: &str, entity: &'static str) -> Result<String, BidsError> {
+    let stripped = label.strip_prefix(prefix).unwrap_or(label);
+    // Check for anything that could be interpreted as a path first, so that
+    // dangerous labels get a more specific error message.
+    if stripped.contains(['/', '\\', '\0']) || stripped.contains("..") || stripped == "." {
+        return Err(BidsError::UnsafeLabel {
+            entity,
+            label: label.into()
+        });
+    }
+    match !stripped.is_empty() && stripped.chars().all(|c| c.is_ascii_alphanumeric()) {
+        true => Ok(stripped.into()),
+        false => Err(BidsError::InvalidLabel {
+            entity,
+            label: label.into()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(ParticipantLabel::new("01").unwrap().as_str(), "01");
+        assert_eq!(ParticipantLabel::new("sub-01").unwrap().as_str(), "01");
+        assert_eq!(ParticipantLabel::new("sub-01").unwrap(), ParticipantLabel::new("01").unwrap());
+        assert_eq!(ParticipantLabel::new("NDARINV1234").unwrap().dir_name(), "sub-NDARINV1234");
+        assert_eq!(SessionLabel::new("ses-baseline").unwrap().as_str(), "baseline");
+        assert_eq!(SessionLabel::new("2").unwrap().dir_name(), "ses-2");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(ParticipantLabel::new(""), Err(BidsError::InvalidLabel { .. })));
+        assert!(matches!(ParticipantLabel::new("sub-"), Err(BidsError::InvalidLabel { .. })));
+        assert!(matches!(ParticipantLabel::new("sub-sub-01"), Err(BidsError::InvalidLabel { .. })));
+        assert!(matches!(ParticipantLabel::new("01_ses-1"), Err(BidsError::InvalidLabel { .. })));
+        assert!(matches!(ParticipantLabel::new(" 01"), Err(BidsError::InvalidLabel { .. })));
+        assert!(matches!(ParticipantLabel::new("01\r"), Err(BidsError::InvalidLabel { .. })));
+    }
+
+    #[test]
+    fn test_unsafe() {
+        for label in &["..", "sub-..", ".", "../01", "01/..", "/etc", "a/b", "a\\b", "01\0", "sub-01/../../x"] {
+            assert!(matches!(ParticipantLabel::new(label), Err(BidsError::UnsafeLabel { .. })), "{:?}", label);
+            assert!(matches!(SessionLabel::new(label), Err(BidsError::UnsafeLabel { .. })), "{:?}", label);
+        }
+    }
+}
@@ -7,14 +7,19 @@
 //! subject into an identical BIDS tree containing just a few subjects.
 //!
 //! The [`ShadowBids`] type "shadows" a real BIDS tree using symlinks.  It owns
-//! symlinks to participant non-specific files such as dataset_description.json,
-//! etc.  The symlinks and shadow bids directory itself are automatically
-//! cleaned up when the `ShadowBids` instance is dropped.  You can create
-//! symlinks to one or more participants inside the shadowed BIDS tree using
-//! a [`BidsParticipant`] for each subject.  These symlinks are also
-//! automatically cleaned up when dropped.  Each instance of `BidsParticipant`
-//! holds a reference to its parent `ShadowBids` such that the `ShadowBids` is
-//! not dropped until all of its child `BidsParticipant`s are dropped.
+//! symlinks to all participant non-specific files and folders in the root of
+//! the tree such as dataset_description.json, top-level sidecars, etc.  The
+//! symlinks and shadow bids directory itself are automatically cleaned up when
+//! the `ShadowBids` instance is dropped.  You can create symlinks to one or
+//! more participants inside the shadowed BIDS tree using a [`BidsParticipant`]
+//! for each subject.  These symlinks are also automatically cleaned up when
+//! dropped.  Each instance of `BidsParticipant` holds a reference to its
+//! parent `ShadowBids` such that the `ShadowBids` is not dropped until all of
+//! its child `BidsParticipant`s are dropped.
+//!
+//! Symlinks are used by default.  Set [`ShadowBidsOptions::link_strategy`] to
+//! use hard links, reflinks, or copies instead, e.g. when symlinks to host
+//! paths won't resolve inside a container.
 //!
 //! See https://bids.neuroimaging.io/
 
@@ -23,8 +28,15 @@ use std::sync::Arc;
 use tempfile::TempDir;
 use thiserror::Error;
 
+mod filter;
+pub use filter::{select_files, EntityFilter};
+mod label;
+pub use label::{ParticipantLabel, SessionLabel};
+mod path;
+pub use path::{find_scans, BidsEntities, BidsPath, BidsScan};
 mod temp;
-use temp::{FileSystemError, NamedTempDir, TempSymlink};
+pub use temp::LinkStrategy;
+use temp::{FileSystemError, NamedTempDir, TempLink};
 
 /// Enumeration over bids-related errors.
 #[derive(Error, Debug)]
@@ -40,7 +52,36 @@ pub enum BidsError {
     #[error("BIDS tree \"{}\" is missing participant \"{}\"", bids_src.to_string_lossy(), participant)]
     MissingParticipant {
         bids_src: PathBuf,
-        participant: String
+        participant: ParticipantLabel
+    },
+    /// A participant or session label was empty or contained characters
+    /// other than letters and digits.
+    #[error("Invalid {} label \"{}\", BIDS labels may only contain letters and digits", entity, label.escape_debug())]
+    InvalidLabel {
+        /// Kind of label, e.g. "participant".
+        entity: &'static str,
+        label: String
+    },
+    /// A participant or session label contained path separators or `..` and
+    /// could refer to a path outside the BIDS tree.
+    #[error("Unsafe {} label \"{}\" could refer to a path outside the BIDS tree", entity, label.escape_debug())]
+    UnsafeLabel {
+        /// Kind of label, e.g. "participant".
+        entity: &'static str,
+        label: String
+    },
+    /// A filename was not a valid BIDS filename of the form
+    /// `<key>-<value>_..._<suffix><extension>`.
+    #[error("Invalid BIDS filename \"{}\"", name.escape_debug())]
+    InvalidName {
+        name: String
+    },
+    /// Tried to create a [`BidsParticipant`] for a session that the
+    /// participant does not have.
+    #[error("Participant \"{}\" is missing session \"{}\"", participant_src.to_string_lossy(), session)]
+    MissingSession {
+        participant_src: PathBuf,
+        session: SessionLabel
     },
     /// Couldn't canonicalize the path to the BIDS tree, or else the BIDS tree
     /// is the filesystem root (which should not happen).
@@ -49,11 +90,48 @@ pub enum BidsError {
         bids_src: PathBuf,
         source: Option<std::io::Error>,
     },
+    /// Couldn't read the contents of a directory in the BIDS tree.
+    #[error("Couldn't read BIDS directory: {}", path.to_string_lossy())]
+    ReadDir {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// Couldn't read a file in the BIDS tree.
+    #[error("Couldn't read BIDS file: {}", path.to_string_lossy())]
+    ReadFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// A tab-separated values file such as participants.tsv is missing a
+    /// required column.
+    #[error("File \"{}\" has no column \"{}\"", path.to_string_lossy(), column)]
+    MissingColumn {
+        path: PathBuf,
+        column: String,
+    },
     /// There was an error performing a filesystem operation.
     #[error(transparent)]
     FileSystem(#[from] FileSystemError),
 }
 
+/// Options for creating a [`ShadowBids`] tree.
+#[derive(Debug, Clone, Default)]
+pub struct ShadowBidsOptions {
+    /// Patterns matching the names of entries in the root of the source BIDS
+    /// tree that should not be shadowed, e.g. `phenotype` or `*.tsv`.  The
+    /// wildcard `*` matches any sequence of characters and `?` matches any
+    /// single character.
+    pub exclude: Vec<String>,
+    /// How files in the root of the source BIDS tree are linked into the
+    /// shadow tree.  Directories other than participants', such as
+    /// `sourcedata` and `derivatives`, are always symlinked, since they can
+    /// be huge and would otherwise be copied for every instance of mriqc.
+    pub link_strategy: LinkStrategy,
+    /// How participants' data is linked into the shadow tree by
+    /// [`BidsParticipant`].  Defaults to `link_strategy`.
+    pub participant_link_strategy: Option<LinkStrategy>,
+}
+
 /// Fake BIDS data structure that shadows a real BIDS data structure.  Intended
 /// to contain one or more [`BidsLink`] symlinks to participant's BIDS-formatted
 /// data.
@@ -66,19 +144,21 @@ pub struct ShadowBids {
     // Path to this directory.
     // Directory will be deleted when this ShadowBids instance is dropped.
     path: NamedTempDir,
-    // Symlink to dataset description, which may or may not exist.
-    _dataset_description: Option<TempSymlink>,
-    // Symlink to sourcedata directory, which may or may not exist.
-    _sourcedata: Option<TempSymlink>,
-    // Symlink to participants.tsv file, which may or may not exist.
-    _participants_tsv: Option<TempSymlink>
+    // Links to every participant non-specific entry in the root of the
+    // source BIDS tree, e.g. dataset_description.json, participants.tsv,
+    // inherited sidecars such as task-rest_bold.json, .bidsignore, etc.
+    _links: Vec<TempLink>,
+    // How entries are linked into this tree.
+    link_strategy: LinkStrategy,
+    // How participants' data is linked into this tree.
+    participant_link_strategy: LinkStrategy
 }
 impl ShadowBids {
     /// Create a new shadow bids tree from the real bids tree located at `src`.
     /// The shadow bids tree will be created at the path `dst`.  If a parent
     /// temporary directory is provided then `dst` will be relative to `parent`
     /// and must not contain `/`.
-    pub async fn new<P1: Into<PathBuf>, P2: Into<PathBuf>>(src: P1, dst: P2, parent: Option<Arc<TempDir>>) -> Result<Self, BidsError> {
+    pub async fn new<P1: Into<PathBuf>, P2: Into<PathBuf>>(src: P1, dst: P2, parent: Option<Arc<TempDir>>, options: &ShadowBidsOptions) -> Result<Self, BidsError> {
         let src = src.into();
         let dst = dst.into();
 
@@ -99,62 +179,68 @@ impl ShadowBids {
         // Create the shadow bids directory.
         let dst = NamedTempDir::new(dst).await?;
 
-        // Create a symlink to the dataset_description.json file, if it exists.
-        let dataset_description = {
-            let src_dataset_description = src.join("dataset_description.json");
-            match exists(&src_dataset_description).await {
-                true => Some(TempSymlink::new(src_dataset_description, dst.path().join("dataset_description.json")).await?),
-                false => None
-            }
+        // Create a link to every entry in the root of the source tree except
+        // for participants' directories and excluded entries.  Under the BIDS
+        // inheritance principle, top-level files such as task-rest_bold.json
+        // may contain metadata that applies to every participant.
+        let read_dir_err = |source| BidsError::ReadDir {
+            path: src.clone(),
+            source
         };
-
-        // Create a symlink to the sourcedata directory, if it exists.
-        let sourcedata = {
-            let src_sourcedata = src.join("sourcedata");
-            match exists(&src_sourcedata).await {
-                true => Some(TempSymlink::new(src_sourcedata, dst.path().join("sourcedata")).await?),
-                false => None
+        let mut links = Vec::new();
+        let mut entries = tokio::fs::read_dir(&src).await.map_err(read_dir_err)?;
+        while let Some(entry) = entries.next_entry().await.map_err(read_dir_err)? {
+            let name = entry.file_name();
+            let skip = match name.to_str() {
+                Some(name) => name.starts_with("sub-") || options.exclude.iter().any(|pattern| matches_pattern(pattern, name)),
+                // Names that aren't valid unicode can't be BIDS participants
+                // and can't match a pattern.
+                None => false
+            };
+            if !skip {
+                let strategy = match is_dir(entry.path()).await {
+                    true => LinkStrategy::Symlink,
+                    false => options.link_strategy
+                };
+                links.push(TempLink::new(entry.path(), dst.path().join(&name), strategy).await?);
             }
-        };
-
-        // Create a symlink to the participants.tsv file, if it exists.
-        let participants_tsv = {
-            let src_participants_tsv = src.join("participants.tsv");
-            match exists(&src_participants_tsv).await {
-                true => Some(TempSymlink::new(src_participants_tsv, dst.path().join("participants.tsv")).await?),
-                false => None
-            }
-        };
+        }
 
         // Compose self.
         Ok(Self {
             parent,
             src,
             path: dst,
-            _dataset_description: dataset_description,
-            _sourcedata: sourcedata,
-            _participants_tsv: participants_tsv
+            _links: links,
+            link_strategy: options.link_strategy,
+            participant_link_strategy: options.participant_link_strategy.unwrap_or(options.link_strategy)
         })
     }
 
     /// Create a new shadow bids tree from the real bids tree located at `src`.
     /// The root of the shadow bids tree will be located at `parent/src`.
-    pub async fn new_with_parent<P1: Into<PathBuf>>(src: P1, parent: Arc<TempDir>) -> Result<Self, BidsError> {
+    pub async fn new_with_parent<P1: Into<PathBuf>>(src: P1, parent: Arc<TempDir>, options: &ShadowBidsOptions) -> Result<Self, BidsError> {
         let src = src.into();
-        let dst: PathBuf = match src.canonicalize() {
-            Ok(path) => match path.file_name() {
-                Some(name) => Ok(name.to_os_string()),
-                None => Err(BidsError::Canonicalize {
-                    bids_src: src.clone(),
-                    source: None
-                })
-            },
-            Err(source) => Err(BidsError::Canonicalize {
-                bids_src: src.clone(),
-                source: Some(source)
-            })
-        }?.into();
-        Self::new(src, dst, Some(parent)).await
+        let dst = shadow_name(&src)?;
+        Self::new(src, dst, Some(parent), options).await
+    }
+
+    /// Create a new shadow bids tree from the real bids tree located at `src`.
+    /// The root of the shadow bids tree will be located at `dir/src`, where
+    /// `dir` is an existing directory that outlives the shadow bids tree.  Any
+    /// previous shadow bids tree at that location, e.g. left behind by a
+    /// killed instance of mriqc1, is removed first.  Useful for giving the
+    /// shadow bids tree the same path every time it is created.
+    pub async fn new_in<P1: Into<PathBuf>, P2: AsRef<Path>>(src: P1, dir: P2, options: &ShadowBidsOptions) -> Result<Self, BidsError> {
+        let src = src.into();
+        let dst = dir.as_ref().join(shadow_name(&src)?);
+        if tokio::fs::symlink_metadata(&dst).await.is_ok() {
+            tokio::fs::remove_dir_all(&dst).await.map_err(|source| FileSystemError::DirRemoveError {
+                path: dst.clone(),
+                source
+            })?;
+        }
+        Self::new(src, dst, None, options).await
     }
 
     /// Get parent temporary directory, if one exists.
@@ -172,6 +258,16 @@ impl ShadowBids {
     pub fn src(&self) -> &Path {
         &self.src
     }
+
+    /// Get the strategy used to link entries into this tree.
+    pub fn link_strategy(&self) -> LinkStrategy {
+        self.link_strategy
+    }
+
+    /// Get the strategy used to link participants' data into this tree.
+    pub fn participant_link_strategy(&self) -> LinkStrategy {
+        self.participant_link_strategy
+    }
 }
 
 /// Symlinks to a participant's BIDS-formatted data.
@@ -179,43 +275,439 @@ pub struct BidsParticipant {
     // Hold reference to parent BIDS tree.
     // ShadowBids is deleted when there are no more references to it.
     parent: Arc<ShadowBids>,
-    // Path to this symlink.
-    // Symlink will be removed when this BidsLink instance is dropped.
-    path: TempSymlink,
+    // Path to the participant's data within the source BIDS tree.
+    src: PathBuf,
+    // Session label if only one of the participant's sessions is linked.
+    session: Option<SessionLabel>,
+    // Links to the participant's data, either a single link to the whole
+    // participant directory, or else links inside `dirs`.
+    // Links will be removed when this BidsParticipant instance is dropped.
+    _links: Vec<TempLink>,
+    // Real directories for the participant inside the shadow BIDS tree, if
+    // only part of the participant's data is linked.  The participant's
+    // directory comes first.
+    _dirs: Vec<NamedTempDir>,
+    // Path to the participant's data within the shadow BIDS tree.
+    path: PathBuf,
 }
 impl BidsParticipant {
-    /// Create a new symlink to a BIDS participant inside a parent BIDS tree.
-    pub async fn new<S: AsRef<str>>(participant: S, parent: Arc<ShadowBids>) -> Result<Self, BidsError> {
-        let participant = participant.as_ref();
-
+    /// Create a new link to a BIDS participant inside a parent BIDS tree.
+    pub async fn new(participant: &ParticipantLabel, parent: Arc<ShadowBids>) -> Result<Self, BidsError> {
         // Does the participant exist within the parent BIDS tree?
-        let sub_str = format!("sub-{}", participant);
-        let src = parent.src().join(&sub_str);
+        let src = parent.src().join(participant.path());
         match exists(&src).await {
             false => Err(BidsError::MissingParticipant{
                 bids_src: parent.src().into(),
-                participant: participant.into()
+                participant: participant.clone()
             }),
             true => {
-                let dst = parent.path().join(sub_str);
+                let dst = parent.path().join(participant.path());
+                let link = TempLink::new(&src, &dst, parent.participant_link_strategy()).await?;
                 Ok(Self {
                     parent,
-                    path: TempSymlink::new(src, dst).await?
+                    src,
+                    session: None,
+                    _links: vec![link],
+                    _dirs: Vec::new(),
+                    path: dst
                 })
             }
         }
     }
+
+    /// Create a new BIDS participant inside a parent BIDS tree containing only
+    /// one of the participant's sessions.  The participant's directory is
+    /// created in the shadow BIDS tree, and inside it are symlinks to the
+    /// session directory and to any files that do not belong to a particular
+    /// session, e.g. `sub-<label>_sessions.tsv`.
+    pub async fn new_session(participant: &ParticipantLabel, session: &SessionLabel, parent: Arc<ShadowBids>) -> Result<Self, BidsError> {
+        // Does the participant exist within the parent BIDS tree?
+        let src = parent.src().join(participant.path());
+        if !exists(&src).await {
+            return Err(BidsError::MissingParticipant{
+                bids_src: parent.src().into(),
+                participant: participant.clone()
+            });
+        }
+        // Does the participant have this session?
+        let ses_str = session.dir_name();
+        if !is_dir(src.join(&ses_str)).await {
+            return Err(BidsError::MissingSession {
+                participant_src: src,
+                session: session.clone()
+            });
+        }
+
+        // Create the participant's directory in the shadow tree.
+        let dst = parent.path().join(participant.path());
+        let dir = NamedTempDir::new(&dst).await?;
+
+        // Link the session and all non-session entries.
+        let read_dir_err = |source| BidsError::ReadDir {
+            path: src.clone(),
+            source
+        };
+        let mut links = Vec::new();
+        let mut entries = tokio::fs::read_dir(&src).await.map_err(read_dir_err)?;
+        while let Some(entry) = entries.next_entry().await.map_err(read_dir_err)? {
+            let name = entry.file_name();
+            let is_other_session = name.to_str()
+                .map(|name| name.starts_with("ses-") && name != ses_str)
+                .unwrap_or(false);
+            if !is_other_session {
+                links.push(TempLink::new(entry.path(), dst.join(&name), parent.participant_link_strategy()).await?);
+            }
+        }
+
+        Ok(Self {
+            parent,
+            src,
+            session: Some(session.clone()),
+            _links: links,
+            _dirs: vec![dir],
+            path: dst
+        })
+    }
+
+    /// Create a new BIDS participant inside a parent BIDS tree containing only
+    /// the participant's files that match `filter`, optionally restricted to
+    /// one session.  Real directories are created in the shadow BIDS tree for
+    /// the participant, its sessions, and its datatypes, and inside them are
+    /// symlinks to each selected file.  See [`select_files()`] for details of
+    /// which files are selected.
+    pub async fn new_filtered(participant: &ParticipantLabel, session: Option<&SessionLabel>, filter: &EntityFilter, parent: Arc<ShadowBids>) -> Result<Self, BidsError> {
+        // Does the participant exist within the parent BIDS tree?
+        let src = parent.src().join(participant.path());
+        if !exists(&src).await {
+            return Err(BidsError::MissingParticipant{
+                bids_src: parent.src().into(),
+                participant: participant.clone()
+            });
+        }
+        // Does the participant have this session?
+        if let Some(session) = session {
+            if !is_dir(src.join(session.path())).await {
+                return Err(BidsError::MissingSession {
+                    participant_src: src,
+                    session: session.clone()
+                });
+            }
+        }
+
+        // Select which files to link.
+        let files = select_files(&src, filter, session).await?;
+
+        // Create the participant's directory in the shadow tree, then the
+        // directories and symlinks for each file.
+        let dst = parent.path().join(participant.path());
+        let mut dirs = vec![NamedTempDir::new(&dst).await?];
+        let mut links = Vec::new();
+        for file in files {
+            // Create any parent directories that don't exist yet.
+            let mut ancestors: Vec<&Path> = file.ancestors()
+                .skip(1)
+                .take_while(|ancestor| !ancestor.as_os_str().is_empty())
+                .collect();
+            ancestors.reverse();
+            for ancestor in ancestors {
+                let dir = dst.join(ancestor);
+                if !dirs.iter().any(|existing| existing.path() == dir) {
+                    dirs.push(NamedTempDir::new(dir).await?);
+                }
+            }
+            links.push(TempLink::new(src.join(&file), dst.join(&file), parent.participant_link_strategy()).await?);
+        }
+
+        Ok(Self {
+            parent,
+            src,
+            session: session.cloned(),
+            _links: links,
+            _dirs: dirs,
+            path: dst
+        })
+    }
+
     /// Get parent BIDS directory tree root for this participant.
     pub fn parent(&self) -> Arc<ShadowBids> {
         self.parent.clone()
     }
     /// Get path to root of this participant's data within the shadow bids tree.
     pub fn path(&self) -> &Path {
-        self.path.dst_path()
+        &self.path
     }
     /// Get path to root of this participant's data within the source bids tree.
     pub fn src(&self) -> &Path {
-        self.path.src_path()
+        &self.src
+    }
+    /// Get the label of the session this participant is restricted to, or
+    /// `None` if all of the participant's sessions are included.
+    pub fn session(&self) -> Option<&SessionLabel> {
+        self.session.as_ref()
+    }
+}
+
+/// Table of participants read from the participants.tsv file in the root of a
+/// BIDS tree.
+pub struct ParticipantsTsv {
+    // Path to the participants.tsv file.
+    path: PathBuf,
+    // Column names from the header row.
+    columns: Vec<String>,
+    // Values in each subsequent row.
+    rows: Vec<Vec<String>>
+}
+impl ParticipantsTsv {
+    /// Read the participants.tsv file from the BIDS tree located at `src`.
+    pub async fn read<P: AsRef<Path>>(src: P) -> Result<Self, BidsError> {
+        let path = src.as_ref().join("participants.tsv");
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(source) => return Err(BidsError::ReadFile { path, source })
+        };
+        let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+        let split = |line: &str| line.split('\t').map(|value| value.trim().to_string()).collect::<Vec<_>>();
+        let columns = lines.next().map(split).unwrap_or_default();
+        let rows = lines.map(split).collect();
+        Ok(Self { path, columns, rows })
+    }
+
+    /// Get the column names from the header row.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Get the labels of participants whose row matches all of the given
+    /// `(column, value)` conditions.  Returns an error if any of the columns,
+    /// or the `participant_id` column, does not exist, or if a matching row
+    /// has an invalid participant label.
+    pub fn filter(&self, conditions: &[(String, String)]) -> Result<Vec<ParticipantLabel>, BidsError> {
+        let id_index = self.column_index("participant_id")?;
+        let conditions = conditions.iter()
+            .map(|(column, value)| Ok((self.column_index(column)?, value.as_str())))
+            .collect::<Result<Vec<_>, BidsError>>()?;
+        self.rows.iter()
+            .filter(|row| conditions.iter().all(|(index, value)|
+                row.get(*index).map(String::as_str) == Some(*value)
+            ))
+            .filter_map(|row| row.get(id_index))
+            .map(ParticipantLabel::new)
+            .collect()
+    }
+
+    // Find the index of a column by name.
+    fn column_index(&self, column: &str) -> Result<usize, BidsError> {
+        self.columns.iter().position(|name| name == column).ok_or_else(||
+            BidsError::MissingColumn {
+                path: self.path.clone(),
+                column: column.into()
+            }
+        )
+    }
+}
+
+/// Report produced by [`validate_participants()`] after resolving requested
+/// participant labels against a BIDS tree.
+#[derive(Debug, Clone, Default)]
+pub struct ParticipantValidation {
+    /// Requested participants that exist in the BIDS tree, in the order they
+    /// were requested, with duplicates removed.
+    pub found: Vec<ParticipantLabel>,
+    /// Requested participants that do not exist in the BIDS tree, each with a
+    /// list of similar labels that do exist.
+    pub missing: Vec<(ParticipantLabel, Vec<ParticipantLabel>)>,
+    /// Participants that were requested more than once.
+    pub duplicated: Vec<ParticipantLabel>,
+}
+impl ParticipantValidation {
+    /// Check if every requested participant exists and was only requested
+    /// once.
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.duplicated.is_empty()
+    }
+}
+impl std::fmt::Display for ParticipantValidation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.missing.is_empty() {
+            write!(f, "{} participant(s) not found in BIDS tree:", self.missing.len())?;
+            for (participant, suggestions) in &self.missing {
+                write!(f, "\n    {}", participant)?;
+                if !suggestions.is_empty() {
+                    write!(f, " (did you mean {}?)", join_labels(suggestions))?;
+                }
+            }
+            if !self.duplicated.is_empty() {
+                writeln!(f)?;
+            }
+        }
+        if !self.duplicated.is_empty() {
+            write!(f, "{} participant(s) given more than once: {}", self.duplicated.len(), join_labels(&self.duplicated))?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolve each of the requested `participants` against the BIDS tree located
+/// at `src` before any processing begins, so that all missing or duplicated
+/// participants can be reported at once.  Missing participants are reported
+/// along with up to three similarly-named participants that do exist.
+pub async fn validate_participants<P: AsRef<Path>>(src: P, participants: &[ParticipantLabel]) -> Result<ParticipantValidation, BidsError> {
+    let available = discover_participants(src).await?;
+    let mut validation = ParticipantValidation::default();
+    let mut seen = std::collections::HashSet::new();
+    for participant in participants {
+        // Only consider the first occurrence of each participant.
+        if !seen.insert(participant) {
+            if !validation.duplicated.contains(participant) {
+                validation.duplicated.push(participant.clone());
+            }
+            continue;
+        }
+        match available.binary_search(participant) {
+            Ok(_) => validation.found.push(participant.clone()),
+            Err(_) => {
+                // Suggest the closest matches, tolerating about one typo for
+                // every three characters.
+                let max_distance = std::cmp::max(1, participant.as_str().chars().count() / 3);
+                let mut suggestions: Vec<(usize, &ParticipantLabel)> = available.iter()
+                    .map(|label| (edit_distance(participant.as_str(), label.as_str()), label))
+                    .filter(|(distance, _)| *distance <= max_distance)
+                    .collect();
+                suggestions.sort();
+                validation.missing.push((
+                    participant.clone(),
+                    suggestions.into_iter().take(3).map(|(_, label)| label.clone()).collect()
+                ));
+            }
+        }
+    }
+    Ok(validation)
+}
+
+/// Discover all participants in the BIDS tree located at `src`.  Returns the
+/// labels of every `sub-*` directory in the root of the tree, sorted so that
+/// the order is deterministic.  Directories whose names are not valid
+/// participant labels are ignored.
+pub async fn discover_participants<P: AsRef<Path>>(src: P) -> Result<Vec<ParticipantLabel>, BidsError> {
+    Ok(discover_labels(src.as_ref(), "sub-").await?
+        .into_iter()
+        .filter_map(|label| ParticipantLabel::new(label).ok())
+        .collect())
+}
+
+/// Discover all sessions of a participant whose data is located at `src`, e.g.
+/// `/bids/sub-01`.  Returns the labels of every `ses-*` directory in the
+/// participant's directory, sorted so that the order is deterministic.
+/// Returns an empty vector if the participant's data is not organized into
+/// sessions.  Directories whose names are not valid session labels are
+/// ignored.
+pub async fn discover_sessions<P: AsRef<Path>>(src: P) -> Result<Vec<SessionLabel>, BidsError> {
+    Ok(discover_labels(src.as_ref(), "ses-").await?
+        .into_iter()
+        .filter_map(|label| SessionLabel::new(label).ok())
+        .collect())
+}
+
+// List the paths of all entries in a directory.
+async fn list_dir(dir: &Path) -> Result<Vec<PathBuf>, BidsError> {
+    let read_dir_err = |source| BidsError::ReadDir {
+        path: dir.into(),
+        source
+    };
+    let mut paths = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await.map_err(read_dir_err)?;
+    while let Some(entry) = entries.next_entry().await.map_err(read_dir_err)? {
+        paths.push(entry.path());
+    }
+    Ok(paths)
+}
+
+// Find the sorted labels of all directories in `src` named <prefix><label>.
+async fn discover_labels(src: &Path, prefix: &str) -> Result<Vec<String>, BidsError> {
+    let read_dir_err = |source| BidsError::ReadDir {
+        path: src.into(),
+        source
+    };
+    let mut labels = Vec::new();
+    let mut entries = tokio::fs::read_dir(src).await.map_err(read_dir_err)?;
+    while let Some(entry) = entries.next_entry().await.map_err(read_dir_err)? {
+        // Only consider directories named <prefix><label>.
+        let name = entry.file_name();
+        let label = match name.to_str().and_then(|name| name.strip_prefix(prefix)) {
+            Some(label) if !label.is_empty() => label.to_string(),
+            _ => continue
+        };
+        // Follow symlinks, e.g. in datalad datasets.
+        if is_dir(entry.path()).await {
+            labels.push(label);
+        }
+    }
+    labels.sort();
+    Ok(labels)
+}
+
+// Join labels with commas for display.
+fn join_labels(labels: &[ParticipantLabel]) -> String {
+    labels.iter().map(ParticipantLabel::as_str).collect::<Vec<_>>().join(", ")
+}
+
+// Levenshtein distance between two strings, i.e. the number of single-
+// character insertions, deletions or substitutions needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the previous prefix of `a` to each prefix of `b`.
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + if ca == *cb { 0 } else { 1 };
+            curr[j + 1] = substitution.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+// Check if `name` matches a `pattern` in which `*` matches any sequence of
+// characters and `?` matches any single character.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Position in pattern and name.
+    let (mut p, mut n) = (0, 0);
+    // Position of the most recent `*` in pattern, and of the character in name
+    // it was matched against, for backtracking.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            },
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                // Let the last `*` match one more character and try again.
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                },
+                None => return false
+            }
+        }
+    }
+    // Any remaining pattern must be all `*`.
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Check if path exists and is a directory.
+async fn is_dir<P: AsRef<Path>>(path: P) -> bool {
+    match tokio::fs::metadata(path.as_ref()).await {
+        Ok(metadata) => metadata.is_dir(),
+        Err(_) => false
     }
 }
 
@@ -223,3 +715,105 @@ impl BidsParticipant {
 async fn exists<P: AsRef<Path>>(path: P) -> bool {
     tokio::fs::metadata(path.as_ref()).await.is_ok()
 }
+
+// Name of the root of a shadow bids tree for the real bids tree at `src`,
+// i.e. the name of the directory `src` after resolving symlinks and `..`.
+fn shadow_name(src: &Path) -> Result<PathBuf, BidsError> {
+    match src.canonicalize() {
+        Ok(path) => match path.file_name() {
+            Some(name) => Ok(name.into()),
+            None => Err(BidsError::Canonicalize {
+                bids_src: src.into(),
+                source: None
+            })
+        },
+        Err(source) => Err(BidsError::Canonicalize {
+            bids_src: src.into(),
+            source: Some(source)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("phenotype", "phenotype"));
+        assert!(!matches_pattern("phenotype", "phenotypes"));
+        assert!(matches_pattern("*.tsv", "participants.tsv"));
+        assert!(!matches_pattern("*.tsv", "participants.json"));
+        assert!(matches_pattern("task-*_bold.json", "task-rest_bold.json"));
+        assert!(matches_pattern("?EADME*", "README.md"));
+        assert!(matches_pattern("*", ".bidsignore"));
+        assert!(!matches_pattern("", "README"));
+    }
+
+    #[tokio::test]
+    async fn test_validate_participants() {
+        let src = tempfile::tempdir().unwrap();
+        for participant in &["sub-01", "sub-02", "sub-susan"] {
+            std::fs::create_dir(src.path().join(participant)).unwrap();
+        }
+        let labels = |labels: &[&str]| labels.iter().map(|label| label.parse().unwrap()).collect::<Vec<ParticipantLabel>>();
+
+        // All present, in the order requested.
+        let validation = validate_participants(src.path(), &labels(&["02", "sub-01"])).await.unwrap();
+        assert!(validation.is_ok());
+        assert_eq!(validation.found, labels(&["02", "01"]));
+
+        // Missing, with suggestions for typos.
+        let validation = validate_participants(src.path(), &labels(&["01", "susn", "bob"])).await.unwrap();
+        assert!(!validation.is_ok());
+        assert_eq!(validation.found, labels(&["01"]));
+        assert_eq!(validation.missing, vec![
+            (labels(&["susn"])[0].clone(), labels(&["susan"])),
+            (labels(&["bob"])[0].clone(), Vec::new())
+        ]);
+        assert!(validation.duplicated.is_empty());
+
+        // Duplicated, reported once however many times it was given.
+        let validation = validate_participants(src.path(), &labels(&["01", "sub-01", "02", "01"])).await.unwrap();
+        assert!(!validation.is_ok());
+        assert_eq!(validation.found, labels(&["01", "02"]));
+        assert!(validation.missing.is_empty());
+        assert_eq!(validation.duplicated, labels(&["01"]));
+    }
+
+    #[tokio::test]
+    async fn test_participants_tsv_filter() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::write(src.path().join("participants.tsv"),
+            "participant_id\tgroup\tsite\n\
+             sub-01\tcontrol\tA\n\
+             sub-02\tpatient\tA\n\
+             \n\
+             sub-03\tcontrol\tB\n\
+             sub-04\t\tB\n"
+        ).unwrap();
+        let tsv = ParticipantsTsv::read(src.path()).await.unwrap();
+        let labels = |labels: &[&str]| labels.iter().map(|label| label.parse().unwrap()).collect::<Vec<ParticipantLabel>>();
+        let condition = |column: &str, value: &str| (column.to_string(), value.to_string());
+
+        assert_eq!(tsv.columns(), ["participant_id", "group", "site"]);
+        assert_eq!(tsv.filter(&[]).unwrap(), labels(&["01", "02", "03", "04"]));
+        assert_eq!(tsv.filter(&[condition("group", "control")]).unwrap(), labels(&["01", "03"]));
+        assert_eq!(tsv.filter(&[condition("group", "control"), condition("site", "B")]).unwrap(), labels(&["03"]));
+        assert_eq!(tsv.filter(&[condition("group", "")]).unwrap(), labels(&["04"]));
+        assert!(tsv.filter(&[condition("site", "C")]).unwrap().is_empty());
+        assert!(matches!(
+            tsv.filter(&[condition("age", "30")]),
+            Err(BidsError::MissingColumn { column, .. }) if column == "age"
+        ));
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("NDARINV1234", "NDARINV1234"), 0);
+        assert_eq!(edit_distance("NDARINV1234", "NDARINV1243"), 2);
+        assert_eq!(edit_distance("01", "001"), 1);
+        assert_eq!(edit_distance("", "bob"), 3);
+        assert_eq!(edit_distance("susan", "susie"), 2);
+    }
+}
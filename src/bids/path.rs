@@ -0,0 +1,280 @@
+//! Parse BIDS filenames such as `sub-01_ses-2_task-rest_run-1_bold.nii.gz`
+//! into their entities, suffix, and extension.
+
+use super::{discover_sessions, is_dir, list_dir, BidsError, ParticipantLabel, SessionLabel};
+use std::path::{Path, PathBuf};
+
+/// Ordered key-value entities of a BIDS filename, e.g. `sub-01_task-rest` is
+/// `[("sub", "01"), ("task", "rest")]`.  Entities keep the order in which they
+/// were parsed or inserted, which for a valid BIDS filename is the order
+/// required by the specification.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct BidsEntities(Vec<(String, String)>);
+impl BidsEntities {
+    /// Create an empty set of entities.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the value of entity `key`, e.g. `rest` for `task`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Set the value of entity `key`, replacing any previous value or else
+    /// appending the entity after the existing ones.
+    pub fn insert<S1: Into<String>, S2: Into<String>>(&mut self, key: S1, value: S2) {
+        let key = key.into();
+        let value = value.into();
+        match self.0.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((key, value))
+        }
+    }
+
+    /// Remove entity `key`, returning its value if it was present.
+    pub fn remove(&mut self, key: &str) -> Option<String> {
+        let index = self.0.iter().position(|(k, _)| k == key)?;
+        Some(self.0.remove(index).1)
+    }
+
+    /// Iterate over the entities' keys and values in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Number of entities.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check if there are no entities.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check if every one of these entities is also in `other` with the same
+    /// value, e.g. `task-rest` is a subset of `sub-01_task-rest_run-1`.
+    pub fn is_subset(&self, other: &BidsEntities) -> bool {
+        self.iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+
+    /// Get the participant label from the `sub` entity, if present and
+    /// valid.
+    pub fn participant(&self) -> Option<ParticipantLabel> {
+        ParticipantLabel::new(self.get("sub")?).ok()
+    }
+
+    /// Get the session label from the `ses` entity, if present and valid.
+    pub fn session(&self) -> Option<SessionLabel> {
+        SessionLabel::new(self.get("ses")?).ok()
+    }
+}
+impl std::str::FromStr for BidsEntities {
+    type Err = BidsError;
+    /// Parse entities from underscore-separated `key-value` pairs, e.g.
+    /// `sub-01_task-rest`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || BidsError::InvalidName { name: s.into() };
+        let mut entities = Self::new();
+        for part in s.split('_') {
+            match part.split_once('-') {
+                Some((key, value)) if !key.is_empty() && !value.is_empty() && !value.contains('-') => {
+                    if entities.get(key).is_some() {
+                        return Err(invalid());
+                    }
+                    entities.insert(key, value);
+                },
+                _ => return Err(invalid())
+            }
+        }
+        Ok(entities)
+    }
+}
+impl std::fmt::Display for BidsEntities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, (key, value)) in self.iter().enumerate() {
+            if index > 0 {
+                write!(f, "_")?;
+            }
+            write!(f, "{}-{}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parsed BIDS filename, e.g. `sub-01_task-rest_bold.nii.gz` has the entities
+/// `sub-01_task-rest`, suffix `bold`, and extension `.nii.gz`.  Formatting a
+/// `BidsPath` with [`Display`](std::fmt::Display) gives back the filename.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BidsPath {
+    entities: BidsEntities,
+    suffix: String,
+    extension: String,
+}
+impl BidsPath {
+    /// Create a new filename from its parts.  The extension should include
+    /// the leading dot, e.g. `.json`, or else be empty.
+    pub fn new<S1: Into<String>, S2: Into<String>>(entities: BidsEntities, suffix: S1, extension: S2) -> Self {
+        Self {
+            entities,
+            suffix: suffix.into(),
+            extension: extension.into()
+        }
+    }
+
+    /// Parse the file name of `path`, e.g. `/bids/sub-01/anat/sub-01_T1w.nii`.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, BidsError> {
+        let path = path.as_ref();
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.parse(),
+            None => Err(BidsError::InvalidName { name: path.to_string_lossy().into() })
+        }
+    }
+
+    /// Get the entities, e.g. `sub-01_task-rest`.
+    pub fn entities(&self) -> &BidsEntities {
+        &self.entities
+    }
+
+    /// Get mutable access to the entities.
+    pub fn entities_mut(&mut self) -> &mut BidsEntities {
+        &mut self.entities
+    }
+
+    /// Get the suffix, e.g. `bold`.
+    pub fn suffix(&self) -> &str {
+        &self.suffix
+    }
+
+    /// Get the extension including the leading dot, e.g. `.nii.gz`.
+    pub fn extension(&self) -> &str {
+        &self.extension
+    }
+
+    /// Get the filename without its extension, e.g. `sub-01_task-rest_bold`.
+    pub fn stem(&self) -> String {
+        format!("{}_{}", self.entities, self.suffix)
+    }
+
+    /// Get a copy of this filename with a different extension, e.g. to find
+    /// the `.json` sidecar of a `.nii.gz` image.
+    pub fn with_extension<S: Into<String>>(&self, extension: S) -> Self {
+        Self {
+            entities: self.entities.clone(),
+            suffix: self.suffix.clone(),
+            extension: extension.into()
+        }
+    }
+
+    /// Check if this is a NIfTI image, i.e. ends in `.nii` or `.nii.gz`.
+    pub fn is_image(&self) -> bool {
+        self.extension == ".nii" || self.extension == ".nii.gz"
+    }
+}
+impl std::str::FromStr for BidsPath {
+    type Err = BidsError;
+    /// Parse a BIDS filename such as `sub-01_task-rest_bold.nii.gz`.  The
+    /// filename must have at least one entity and a suffix.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let invalid = || BidsError::InvalidName { name: name.into() };
+        let (stem, extension) = match name.find('.') {
+            Some(index) => name.split_at(index),
+            None => (name, "")
+        };
+        let (entities, suffix) = stem.rsplit_once('_').ok_or_else(invalid)?;
+        if suffix.is_empty() || suffix.contains('-') {
+            return Err(invalid());
+        }
+        Ok(Self {
+            entities: entities.parse().map_err(|_| invalid())?,
+            suffix: suffix.into(),
+            extension: extension.into()
+        })
+    }
+}
+impl std::fmt::Display for BidsPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}_{}{}", self.entities, self.suffix, self.extension)
+    }
+}
+
+/// A participant's scan, i.e. a NIfTI image with a valid BIDS filename in one
+/// of the participant's datatype directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BidsScan {
+    /// Path to the image.
+    pub path: PathBuf,
+    /// Datatype directory containing the image, e.g. `anat`.
+    pub datatype: String,
+    /// Parsed filename of the image.
+    pub name: BidsPath,
+}
+
+/// Find all of a participant's scans in the BIDS tree located at `src`,
+/// optionally restricted to one session.  Scans are found in the datatype
+/// directories (e.g. `anat`, `func`) directly inside the participant's
+/// directory and inside each of its session directories.  Images whose names
+/// aren't valid BIDS filenames are ignored.  Returns the scans sorted by path.
+pub async fn find_scans<P: AsRef<Path>>(src: P, participant: &ParticipantLabel, session: Option<&SessionLabel>) -> Result<Vec<BidsScan>, BidsError> {
+    let participant_src = src.as_ref().join(participant.path());
+    // Directories that may contain datatype directories.
+    let roots = match session {
+        Some(session) => vec![participant_src.join(session.path())],
+        None => {
+            let mut roots = vec![participant_src.clone()];
+            roots.extend(discover_sessions(&participant_src).await?.into_iter().map(|session|
+                participant_src.join(session.path())
+            ));
+            roots
+        }
+    };
+    let mut scans = Vec::new();
+    for root in roots {
+        for datatype_dir in list_dir(&root).await? {
+            let datatype = match datatype_dir.file_name().and_then(|name| name.to_str()) {
+                Some(datatype) if !datatype.starts_with("ses-") => datatype.to_string(),
+                _ => continue
+            };
+            if !is_dir(&datatype_dir).await {
+                continue;
+            }
+            for path in list_dir(&datatype_dir).await? {
+                if let Ok(name) = BidsPath::from_path(&path) {
+                    if name.is_image() {
+                        scans.push(BidsScan { path, datatype: datatype.clone(), name });
+                    }
+                }
+            }
+        }
+    }
+    scans.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(scans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let name: BidsPath = "sub-01_ses-2_task-rest_run-1_bold.nii.gz".parse().unwrap();
+        assert_eq!(name.entities().iter().collect::<Vec<_>>(), vec![("sub", "01"), ("ses", "2"), ("task", "rest"), ("run", "1")]);
+        assert_eq!(name.suffix(), "bold");
+        assert_eq!(name.extension(), ".nii.gz");
+        assert_eq!(name.stem(), "sub-01_ses-2_task-rest_run-1_bold");
+        assert_eq!(name.to_string(), "sub-01_ses-2_task-rest_run-1_bold.nii.gz");
+        assert_eq!(name.with_extension(".json").to_string(), "sub-01_ses-2_task-rest_run-1_bold.json");
+        assert_eq!(name.entities().participant(), Some(ParticipantLabel::new("01").unwrap()));
+        assert_eq!(name.entities().session(), Some(SessionLabel::new("2").unwrap()));
+        assert!(name.is_image());
+        let sidecar: BidsPath = "task-rest_bold.json".parse().unwrap();
+        assert!(sidecar.entities().is_subset(name.entities()));
+        assert!(!name.entities().is_subset(sidecar.entities()));
+        for invalid in &["README", "dataset_description.json", "sub-01.nii", "sub-01_task-a-b_bold.nii", "sub-01_sub-02_T1w.nii", "sub-01_T1w-x.nii"] {
+            assert!(invalid.parse::<BidsPath>().is_err(), "{:?}", invalid);
+        }
+    }
+}
@@ -10,6 +10,7 @@ use thiserror::Error;
 
 /// Custom error type adds the offending path to [`std::io::Error`].
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum FileSystemError {
     /// Directory creation failed.
     #[error("Could not create: {}", path.to_string_lossy())]
@@ -36,6 +37,73 @@ pub enum FileSystemError {
         src_path: PathBuf,
         dst_path: PathBuf,
         source: tokio::io::Error
+    },
+    /// Hard link creation failed.
+    #[error("Could not create hard link \"{}\" to \"{}\".", dst_path.to_string_lossy(), src_path.to_string_lossy())]
+    HardlinkCreateError {
+        src_path: PathBuf,
+        dst_path: PathBuf,
+        source: tokio::io::Error
+    },
+    /// Copying or reflinking a file failed.
+    #[error("Could not copy \"{}\" to \"{}\".", src_path.to_string_lossy(), dst_path.to_string_lossy())]
+    CopyError {
+        src_path: PathBuf,
+        dst_path: PathBuf,
+        source: tokio::io::Error
+    },
+    /// File removal failed.
+    #[error("Could not remove: {}", path.to_string_lossy())]
+    FileRemoveError {
+        path: PathBuf,
+        source: tokio::io::Error
+    },
+    /// Reading the contents of a directory failed.
+    #[error("Could not read directory: {}", path.to_string_lossy())]
+    DirReadError {
+        path: PathBuf,
+        source: tokio::io::Error
+    }
+}
+
+/// How a file or directory from a source tree is made to appear in a
+/// temporary tree.  See [`TempLink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkStrategy {
+    /// Symbolic link to the source.  Cheapest, but symlinks may not resolve
+    /// inside a container and some filesystems don't allow them.  This is the
+    /// default.
+    #[default]
+    Symlink,
+    /// Hard link to each file.  The temporary tree must be on the same
+    /// filesystem as the source.
+    Hardlink,
+    /// Copy-on-write clone of each file on filesystems that support it (e.g.
+    /// btrfs, XFS), falling back to a copy.
+    Reflink,
+    /// Copy of each file.
+    Copy,
+}
+impl std::str::FromStr for LinkStrategy {
+    type Err = String;
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "symlink" => Ok(LinkStrategy::Symlink),
+            "hardlink" => Ok(LinkStrategy::Hardlink),
+            "reflink" => Ok(LinkStrategy::Reflink),
+            "copy" => Ok(LinkStrategy::Copy),
+            _ => Err(format!("unknown link mode \"{}\", expected symlink, hardlink, reflink, or copy", strategy))
+        }
+    }
+}
+impl std::fmt::Display for LinkStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            LinkStrategy::Symlink => "symlink",
+            LinkStrategy::Hardlink => "hardlink",
+            LinkStrategy::Reflink => "reflink",
+            LinkStrategy::Copy => "copy"
+        })
     }
 }
 
@@ -174,3 +242,326 @@ impl Drop for TempSymlink {
         }
     }
 }
+
+/// Temporary hard link to a file.
+pub struct TempHardlink {
+    // Has close() been called?
+    closed: bool,
+    // Filesystem path of the linked file.
+    src_path: PathBuf,
+    // Filesystem path of this hard link.
+    dst_path: PathBuf
+}
+impl TempHardlink {
+    /// Close and remove the hard link, leaving the source file intact.
+    pub async fn close(&mut self) -> Result<(), FileSystemError> {
+        if !self.closed {
+            tokio::fs::remove_file(&self.dst_path).await.map_err( |source|
+                FileSystemError::FileRemoveError {
+                    path: self.dst_path.clone(),
+                    source
+                }
+            )?;
+            self.closed = true;
+        }
+        Ok(())
+    }
+
+    /// Get the filesystem path of this hard link.
+    pub fn dst_path(&self) -> &Path {
+        &self.dst_path
+    }
+
+    /// Check if close() has been called.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Create a new temporary hard link at `dst` to the file `src`.  If `src`
+    /// is a symlink then the file it points to is linked.
+    pub async fn new<P1: Into<PathBuf>, P2: Into<PathBuf>>(src: P1, dst: P2) -> Result<Self, FileSystemError> {
+        let src = src.into();
+        let dst = dst.into();
+        let res = match tokio::fs::canonicalize(&src).await {
+            Ok(target) => tokio::fs::hard_link(&target, &dst).await,
+            Err(e) => Err(e)
+        };
+        match res {
+            Err(source) => Err(FileSystemError::HardlinkCreateError {
+                src_path: src,
+                dst_path: dst,
+                source
+            }),
+            Ok(_) => Ok(Self {
+                closed: false,
+                src_path: src,
+                dst_path: dst
+            })
+        }
+    }
+
+    /// Get the filesystem path of the linked file.
+    pub fn src_path(&self) -> &Path {
+        &self.src_path
+    }
+}
+impl Drop for TempHardlink {
+    fn drop(&mut self) {
+        if !self.closed {
+            // On destruction, remove the hard link.
+            let _ = std::fs::remove_file(&self.dst_path);
+        }
+    }
+}
+
+/// Temporary copy of a file, which may be a copy-on-write clone (reflink) of
+/// the source file.
+pub struct TempCopy {
+    // Has close() been called?
+    closed: bool,
+    // Filesystem path of the copied file.
+    src_path: PathBuf,
+    // Filesystem path of this copy.
+    dst_path: PathBuf
+}
+impl TempCopy {
+    /// Close and remove the copy.
+    pub async fn close(&mut self) -> Result<(), FileSystemError> {
+        if !self.closed {
+            tokio::fs::remove_file(&self.dst_path).await.map_err( |source|
+                FileSystemError::FileRemoveError {
+                    path: self.dst_path.clone(),
+                    source
+                }
+            )?;
+            self.closed = true;
+        }
+        Ok(())
+    }
+
+    /// Get the filesystem path of this copy.
+    pub fn dst_path(&self) -> &Path {
+        &self.dst_path
+    }
+
+    /// Check if close() has been called.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Create a new temporary copy at `dst` of the file `src`.
+    pub async fn new<P1: Into<PathBuf>, P2: Into<PathBuf>>(src: P1, dst: P2) -> Result<Self, FileSystemError> {
+        let src = src.into();
+        let dst = dst.into();
+        match tokio::fs::copy(&src, &dst).await {
+            Err(source) => Err(FileSystemError::CopyError {
+                src_path: src,
+                dst_path: dst,
+                source
+            }),
+            Ok(_) => Ok(Self {
+                closed: false,
+                src_path: src,
+                dst_path: dst
+            })
+        }
+    }
+
+    /// Create a new temporary copy-on-write clone at `dst` of the file `src`.
+    /// If the filesystem doesn't support cloning, e.g. because `src` and `dst`
+    /// are on different filesystems, then fall back to copying the file.
+    pub async fn new_reflink<P1: Into<PathBuf>, P2: Into<PathBuf>>(src: P1, dst: P2) -> Result<Self, FileSystemError> {
+        let src = src.into();
+        let dst = dst.into();
+        if reflink(&src, &dst).await {
+            return Ok(Self {
+                closed: false,
+                src_path: src,
+                dst_path: dst
+            });
+        }
+        Self::new(src, dst).await
+    }
+
+    /// Get the filesystem path of the copied file.
+    pub fn src_path(&self) -> &Path {
+        &self.src_path
+    }
+}
+impl Drop for TempCopy {
+    fn drop(&mut self) {
+        if !self.closed {
+            // On destruction, remove the copy.
+            let _ = std::fs::remove_file(&self.dst_path);
+        }
+    }
+}
+
+// Try to clone the file `src` to a new file `dst` with the FICLONE ioctl.
+// Returns true on success.  On failure, no file is left at `dst`.
+#[cfg(target_os = "linux")]
+async fn reflink(src: &Path, dst: &Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+    // _IOW(0x94, 9, int) from linux/fs.h
+    const FICLONE: libc::c_ulong = 0x40049409;
+    let src_file = match tokio::fs::File::open(src).await {
+        Ok(file) => file,
+        Err(_) => return false
+    };
+    let permissions = match src_file.metadata().await {
+        Ok(metadata) => metadata.permissions(),
+        Err(_) => return false
+    };
+    let dst_file = match tokio::fs::OpenOptions::new().write(true).create_new(true).open(dst).await {
+        Ok(file) => file,
+        Err(_) => return false
+    };
+    // Safe because both file descriptors are open for the duration of the
+    // call.
+    let res = unsafe {
+        libc::ioctl(dst_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd())
+    };
+    drop(dst_file);
+    if res != 0 || tokio::fs::set_permissions(dst, permissions).await.is_err() {
+        let _ = tokio::fs::remove_file(dst).await;
+        return false;
+    }
+    true
+}
+#[cfg(not(target_os = "linux"))]
+async fn reflink(_src: &Path, _dst: &Path) -> bool {
+    false
+}
+
+/// Temporary link to a file or directory made with any [`LinkStrategy`].  With
+/// [`LinkStrategy::Symlink`] a single symlink is made to the source, even if
+/// it is a directory.  With the other strategies, directories are mirrored by
+/// real directories containing links or copies of each file, and symlinks to
+/// directories inside them are copied as symlinks.  Everything is removed
+/// when the `TempLink` is dropped.
+pub enum TempLink {
+    /// Symlink to a file or directory.
+    Symlink(TempSymlink),
+    /// Hard link to a file.
+    Hardlink(TempHardlink),
+    /// Copy or reflink of a file.
+    Copy(TempCopy),
+    /// Mirror of a directory, removed with all its contents.
+    Dir(NamedTempDir)
+}
+impl TempLink {
+    /// Create a new temporary link at `dst` to the file or directory `src`.
+    pub async fn new<P1: Into<PathBuf>, P2: Into<PathBuf>>(src: P1, dst: P2, strategy: LinkStrategy) -> Result<Self, FileSystemError> {
+        let src = src.into();
+        let dst = dst.into();
+        if strategy == LinkStrategy::Symlink {
+            return Ok(TempLink::Symlink(TempSymlink::new(src, dst).await?));
+        }
+        match tokio::fs::metadata(&src).await {
+            Ok(metadata) if metadata.is_dir() => {
+                let dir = NamedTempDir::new(&dst).await?;
+                // Walk the source tree without recursion.  Files inside the
+                // mirror are cleaned up along with `dir`.
+                let mut pending = vec![(src, dst)];
+                while let Some((src_dir, dst_dir)) = pending.pop() {
+                    let read_dir_err = |source| FileSystemError::DirReadError {
+                        path: src_dir.clone(),
+                        source
+                    };
+                    let mut entries = tokio::fs::read_dir(&src_dir).await.map_err(read_dir_err)?;
+                    while let Some(entry) = entries.next_entry().await.map_err(read_dir_err)? {
+                        let src_path = entry.path();
+                        let dst_path = dst_dir.join(entry.file_name());
+                        match tokio::fs::symlink_metadata(&src_path).await.map(|metadata| metadata.file_type()) {
+                            Ok(file_type) if file_type.is_dir() => {
+                                tokio::fs::create_dir(&dst_path).await.map_err(|source| FileSystemError::DirCreateError {
+                                    path: dst_path.clone(),
+                                    source
+                                })?;
+                                pending.push((src_path, dst_path));
+                            },
+                            // Symlinks to directories may form loops, so
+                            // symlink them rather than walking them.  Symlinks
+                            // to files, e.g. into a datalad dataset's annex,
+                            // are followed below.
+                            Ok(file_type) if file_type.is_symlink() && super::is_dir(&src_path).await => {
+                                Self::new_file(src_path, dst_path, LinkStrategy::Symlink).await?.forget();
+                            },
+                            // Broken symlinks and the like get linked as files
+                            // so that the error message names them.
+                            _ => {
+                                // Dropping the file's guard would remove it,
+                                // so hand responsibility to `dir`.
+                                Self::new_file(src_path, dst_path, strategy).await?.forget();
+                            }
+                        }
+                    }
+                }
+                Ok(TempLink::Dir(dir))
+            },
+            _ => Self::new_file(src, dst, strategy).await
+        }
+    }
+
+    // Link a single file with a strategy other than symlink.
+    async fn new_file(src: PathBuf, dst: PathBuf, strategy: LinkStrategy) -> Result<Self, FileSystemError> {
+        Ok(match strategy {
+            LinkStrategy::Symlink => TempLink::Symlink(TempSymlink::new(src, dst).await?),
+            LinkStrategy::Hardlink => TempLink::Hardlink(TempHardlink::new(src, dst).await?),
+            LinkStrategy::Reflink => TempLink::Copy(TempCopy::new_reflink(src, dst).await?),
+            LinkStrategy::Copy => TempLink::Copy(TempCopy::new(src, dst).await?)
+        })
+    }
+
+    // Leave the link in place without removing it on drop.
+    fn forget(self) {
+        match self {
+            TempLink::Symlink(mut link) => link.closed = true,
+            TempLink::Hardlink(mut link) => link.closed = true,
+            TempLink::Copy(mut copy) => copy.closed = true,
+            TempLink::Dir(mut dir) => dir.closed = true
+        }
+    }
+
+    /// Get the filesystem path of this link.
+    pub fn dst_path(&self) -> &Path {
+        match self {
+            TempLink::Symlink(link) => link.dst_path(),
+            TempLink::Hardlink(link) => link.dst_path(),
+            TempLink::Copy(copy) => copy.dst_path(),
+            TempLink::Dir(dir) => dir.path()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_temp_link_dir() {
+        let src = tempfile::tempdir().unwrap();
+        std::fs::create_dir(src.path().join("anat")).unwrap();
+        std::fs::write(src.path().join("anat").join("sub-01_T1w.nii"), b"data").unwrap();
+        let dst = tempfile::tempdir().unwrap();
+        for strategy in &[LinkStrategy::Hardlink, LinkStrategy::Reflink, LinkStrategy::Copy] {
+            let dst_path = dst.path().join(strategy.to_string());
+            let link = TempLink::new(src.path(), &dst_path, *strategy).await.unwrap();
+            let file = dst_path.join("anat").join("sub-01_T1w.nii");
+            assert!(!std::fs::symlink_metadata(&dst_path).unwrap().file_type().is_symlink());
+            assert_eq!(std::fs::read(&file).unwrap(), b"data");
+            drop(link);
+            assert!(!dst_path.exists());
+        }
+        assert!(src.path().join("anat").join("sub-01_T1w.nii").exists());
+
+        // A symlink loop is symlinked rather than walked forever.
+        std::os::unix::fs::symlink("..", src.path().join("anat").join("loop")).unwrap();
+        let dst_path = dst.path().join("loop");
+        let link = TempLink::new(src.path(), &dst_path, LinkStrategy::Copy).await.unwrap();
+        let loop_path = dst_path.join("anat").join("loop");
+        assert!(std::fs::symlink_metadata(&loop_path).unwrap().file_type().is_symlink());
+        drop(link);
+        assert!(!dst_path.exists());
+    }
+}
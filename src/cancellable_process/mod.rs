@@ -1,19 +1,102 @@
 //! Wraps a [`tokio::process::Child`] in a [`CancellableChild`] which can be
 //! cancelled asynchronously using a closure while `wait()`ing for it to finish.
+//!
+//! The closure is only called when the `wait()` future is polled, i.e. when
+//! the child's pipes or exit wake the task.  A silent, hung child may never
+//! wake the task, so use [`CancellableChild::with_deadline()`] to cancel it at
+//! a given time.  Deadlines are backed by [`tokio::time`] and wake the task on
+//! schedule.
+//!
+//! Cancelling a child sends it a signal, and if it is still running after a
+//! grace period, escalates to a stronger signal according to its
+//! [`Escalation`] policy.  The `wait()` futures only resolve once the child
+//! has exited and been reaped, so that resources the child is using can be
+//! safely cleaned up afterwards.
+//!
+//! A child that spawns processes of its own should be started with
+//! [`CancellableChild::spawn_group()`] in a new process group.  Cancellation
+//! signals are then delivered to the whole group, so that grandchildren don't
+//! keep running as orphans after the child is cancelled.
+//!
+//! On Linux, signals are sent through a [`pidfd::PidFd`] where possible, and
+//! a process group is only signalled while the pidfd shows that its leader
+//! hasn't been reaped or a probe shows that it still has members, so that a
+//! process that reused the child's process id after the child was reaped
+//! can't receive them by mistake.
 
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
-use tokio::process::{Child, ChildStdin, ChildStdout, ChildStderr};
+use std::time::{Duration, Instant};
+use tokio::process::{Child, ChildStdin, ChildStdout, ChildStderr, Command};
+use tokio::time::Sleep;
+
+mod pidfd;
+use pidfd::PidFd;
 
 /// How to signal cancellation to a child process.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CancelSignal {
     /// On unix platforms, send the child process SIGINT.
     Interrupt,
+    /// On unix platforms, send the child process SIGTERM.
+    Terminate,
     /// On unix platforms, send the child process SIGKILL.
     Kill
 }
+impl CancelSignal {
+    // The corresponding unix signal.
+    fn signum(&self) -> libc::c_int {
+        match self {
+            CancelSignal::Interrupt => libc::SIGINT,
+            CancelSignal::Terminate => libc::SIGTERM,
+            CancelSignal::Kill => libc::SIGKILL
+        }
+    }
+}
+impl std::fmt::Display for CancelSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            CancelSignal::Interrupt => "SIGINT",
+            CancelSignal::Terminate => "SIGTERM",
+            CancelSignal::Kill => "SIGKILL"
+        })
+    }
+}
+
+/// Policy for escalating cancellation of a child process that doesn't exit.
+/// A child cancelled with [`CancelSignal::Interrupt`] is sent
+/// [`CancelSignal::Terminate`] if it is still running after
+/// `interrupt_grace`, and then [`CancelSignal::Kill`] if it is still running
+/// after a further `terminate_grace`.  A child cancelled with
+/// [`CancelSignal::Terminate`] starts at the second step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Escalation {
+    /// How long to wait after SIGINT before sending SIGTERM.
+    pub interrupt_grace: Duration,
+    /// How long to wait after SIGTERM before sending SIGKILL.
+    pub terminate_grace: Duration,
+}
+impl Escalation {
+    // Next signal to send if the child is still running `grace` after
+    // `signal`, or `None` if there is no stronger signal.
+    fn next(&self, signal: CancelSignal) -> Option<(Duration, CancelSignal)> {
+        match signal {
+            CancelSignal::Interrupt => Some((self.interrupt_grace, CancelSignal::Terminate)),
+            CancelSignal::Terminate => Some((self.terminate_grace, CancelSignal::Kill)),
+            CancelSignal::Kill => None
+        }
+    }
+}
+impl Default for Escalation {
+    /// Wait 30 seconds after SIGINT and 10 seconds after SIGTERM.
+    fn default() -> Self {
+        Self {
+            interrupt_grace: Duration::from_secs(30),
+            terminate_grace: Duration::from_secs(10)
+        }
+    }
+}
 
 /// Exit status of a completed child process.
 #[derive(Debug, Clone, Copy)]
@@ -21,10 +104,15 @@ pub struct ExitStatus {
     /// How the process was cancelled, or `None` of the process was not
     /// cancelled.
     pub how_cancelled: Option<CancelSignal>,
-    /// Exit status of the process.  May be `None` if child was cancelled but
-    /// has not yet exited.  Guaranteed to be `Some` if `how_cancelled` is
-    /// `None`.
-    pub status: Option<std::process::ExitStatus>
+    /// The last signal sent to the process before it exited, which is
+    /// stronger than `how_cancelled` if cancellation was escalated, or `None`
+    /// if the process was not cancelled.
+    pub stopped_by: Option<CancelSignal>,
+    /// Whether the process was cancelled because it reached its deadline, see
+    /// [`CancellableChild::with_deadline()`].
+    pub timed_out: bool,
+    /// Exit status of the process.
+    pub status: std::process::ExitStatus
 }
 
 /// Output of a completed child process.
@@ -33,9 +121,16 @@ pub struct Output {
     /// How the process was cancelled, or `None` of the process was not
     /// cancelled.
     pub how_cancelled: Option<CancelSignal>,
-    /// Output of the process.  May be `None` if child was cancelled but has not
-    /// yet exited.  Guaranteed to be `Some` if `how_cancelled` is `None`.
-    pub output: Option<std::process::Output>
+    /// The last signal sent to the process before it exited, which is
+    /// stronger than `how_cancelled` if cancellation was escalated, or `None`
+    /// if the process was not cancelled.
+    pub stopped_by: Option<CancelSignal>,
+    /// Whether the process was cancelled because it reached its deadline, see
+    /// [`CancellableChild::with_deadline()`].
+    pub timed_out: bool,
+    /// Output of the process, including anything it wrote before it was
+    /// cancelled.
+    pub output: std::process::Output
 }
 
 /// Structure representing a [`tokio::process::Child`] that can be cancelled
@@ -48,12 +143,13 @@ pub struct CancellableChild<F> {
     pub stdout: Option<ChildStdout>,
     /// See [`tokio::process::Child::stderr`].
     pub stderr: Option<ChildStderr>,
+    // Progress of cancelling the child process.  Declared before `child` so
+    // that the process group is killed before the child can be reaped.
+    state: CancelState,
     // The wrapped child process.
     child: Child,
     // Closure that checks whether and how to cancel process.
     check_cancel: F,
-    // How the child process was cancelled, or None if it was not cancelled.
-    how_cancelled: Option<CancelSignal>,
     // The child process's exit status, or None if it is not finished.
     exit_status: Option<std::process::ExitStatus>
 }
@@ -63,19 +159,66 @@ impl<F: FnMut() -> Option<CancelSignal> + Unpin> CancellableChild<F> {
     /// process should be cancelled.  The closure takes no arguments and must
     /// return a [`CancelSignal`] specifying which signal to cancel the child
     /// process with, or else `None` if the child process should not be
-    /// cancelled.
+    /// cancelled.  Cancellation is escalated according to
+    /// [`Escalation::default()`] unless another policy is given with
+    /// [`CancellableChild::with_escalation()`].
     pub fn new(child: Child, f: F) -> Self {
         let mut child = child;
         let stdin = child.stdin.take();
         let stdout = child.stdout.take();
         let stderr = child.stderr.take();
+        let id = child.id();
         Self {
             stdin, stdout, stderr, child,
             check_cancel: f,
-            how_cancelled: None,
+            state: CancelState::new(id),
             exit_status: None
         }
     }
+    /// Spawn `command` as the leader of a new process group and wrap it in a
+    /// `CancellableChild`, see [`CancellableChild::new()`].  Cancellation
+    /// signals are sent to every process in the group, and any members of the
+    /// group that are still running after the child exits due to
+    /// cancellation are killed.  If the `CancellableChild`, or the future
+    /// returned by one of its `wait()` methods, is dropped before the child
+    /// exits then the whole group is killed.  Note that the group no longer
+    /// receives signals from the terminal, e.g. Ctrl+C.
+    pub fn spawn_group(command: &mut Command, f: F) -> std::io::Result<Self> {
+        unsafe {
+            // Unsafe because the closure runs in the forked child before
+            // exec, where only async-signal-safe functions such as setpgid()
+            // may be called.
+            command.pre_exec(|| match libc::setpgid(0, 0) {
+                0 => Ok(()),
+                _ => Err(std::io::Error::last_os_error())
+            });
+        }
+        let mut child = Self::new(command.spawn()?, f);
+        child.state.group = true;
+        Ok(child)
+    }
+    /// Cancel the child process with [`CancelSignal::Interrupt`] if it is
+    /// still running at `deadline`.  Unlike the cancellation closure, which is
+    /// only checked when the child's pipes or exit wake the waiting task, the
+    /// deadline wakes the task on schedule.  Cancellation at the deadline is
+    /// reported by the `timed_out` field of [`ExitStatus`] and [`Output`].
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.state.deadline = Timer::new(Some(deadline));
+        self
+    }
+    /// Escalate cancellation according to `escalation` if the child process
+    /// doesn't exit.
+    pub fn with_escalation(mut self, escalation: Escalation) -> Self {
+        self.state.escalation = escalation;
+        self
+    }
+    /// Also check the cancellation closure when `wakeup` resolves, e.g. when
+    /// a signal handler notices Ctrl+C, rather than only when the child's
+    /// pipes or exit wake the waiting task.
+    pub fn with_wakeup<W: Future<Output = ()> + Send + 'static>(mut self, wakeup: W) -> Self {
+        self.state.wakeup = Some(Box::pin(wakeup));
+        self
+    }
     /// See [`tokio::process::Child::id()`].
     pub fn id(&self) -> Option<u32> {
         self.child.id()
@@ -88,21 +231,19 @@ impl<F: FnMut() -> Option<CancelSignal> + Unpin> CancellableChild<F> {
     pub async fn kill(&mut self) -> std::io::Result<()> {
         self.child.kill().await
     }
-    /// Similar to ['tokio::process::Child::wait()`], but the returned `Future`
-    /// will cancel the process and resolve immediately if the cancellation
+    /// Similar to ['tokio::process::Child::wait()`], but if the cancellation
     /// closure provided to [`CancellableChild::new()`] returns some
-    /// [`CancelSignal`].
+    /// [`CancelSignal`] then the returned `Future` will signal the process,
+    /// escalating if necessary, and resolve as soon as the process exits.
     pub fn wait(&mut self) -> ChildWaitFuture<'_, F, impl '_ + Future<Output = std::io::Result<std::process::ExitStatus>>> {
         // Destructure, then create future.
-        let id = self.id();
         let check_cancel = &mut self.check_cancel;
-        let how_cancelled = &mut self.how_cancelled;
+        let state = &mut self.state;
         let exit_status = &mut self.exit_status;
         let fut = Box::pin(self.child.wait());
         ChildWaitFuture {
-            id,
             check_cancel,
-            how_cancelled,
+            state,
             exit_status,
             fut
         }
@@ -110,18 +251,15 @@ impl<F: FnMut() -> Option<CancelSignal> + Unpin> CancellableChild<F> {
     /// See [`tokio::process::Child::try_wait()`].
     pub fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
         self.exit_status = self.child.try_wait()?;
-        Ok(self.exit_status.map(|status| ExitStatus {
-            how_cancelled: self.how_cancelled,
-            status: Some(status)
-        }))
+        self.state.finished = self.exit_status.is_some();
+        Ok(self.exit_status.map(|status| self.state.exit_status(status)))
     }
     /// See [tokio::process::Child::wait_with_output()`] and
     /// and [`CancellableChild::wait()`].
     pub fn wait_with_output(self) -> ChildWaitOutputFuture<F, impl Future<Output = std::io::Result<std::process::Output>>> {
         // Destructure.
-        let id = self.id();
         let check_cancel = self.check_cancel;
-        let how_cancelled = self.how_cancelled;
+        let state = self.state;
         let mut child = self.child;
         // Put i/o back in child.
         child.stdin = self.stdin;
@@ -130,15 +268,16 @@ impl<F: FnMut() -> Option<CancelSignal> + Unpin> CancellableChild<F> {
         // Create future.
         let fut = Box::pin(child.wait_with_output());
         ChildWaitOutputFuture {
-            id,
             check_cancel,
-            how_cancelled,
+            state,
             fut
         }
     }
     /// Consume this `CancellableChild` and get the inner
-    /// [`tokio::process::Child`].
-    pub fn into_child(self) -> Child {
+    /// [`tokio::process::Child`].  The process group, if any, is no longer
+    /// killed when this is dropped.
+    pub fn into_child(mut self) -> Child {
+        self.state.finished = true;
         let mut child = self.child;
         child.stdin = self.stdin;
         child.stdout = self.stdout;
@@ -147,13 +286,210 @@ impl<F: FnMut() -> Option<CancelSignal> + Unpin> CancellableChild<F> {
     }
 }
 
+// Timer that wakes the waiting task at a given time.  The underlying `Sleep`
+// is created on first poll, because creating it requires a tokio runtime
+// whereas creating the future does not.
+#[derive(Debug)]
+struct Timer {
+    deadline: Option<Instant>,
+    sleep: Option<Pin<Box<Sleep>>>
+}
+impl Timer {
+    fn new(deadline: Option<Instant>) -> Self {
+        Self { deadline, sleep: None }
+    }
+    // Check if the deadline has passed, and if not schedule a wakeup for it.
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        let deadline = match self.deadline {
+            Some(deadline) => deadline,
+            None => return false
+        };
+        let sleep = self.sleep.get_or_insert_with(||
+            Box::pin(tokio::time::sleep_until(tokio::time::Instant::from_std(deadline)))
+        );
+        sleep.as_mut().poll(cx).is_ready()
+    }
+}
+
+// Progress of cancelling a child process, shared by the `wait()` futures.
+struct CancelState {
+    // Process id of the child.
+    id: Option<u32>,
+    // File descriptor referring to the child, if supported.
+    pidfd: Option<PidFd>,
+    // Is the child the leader of its own process group?
+    group: bool,
+    // Has the child exited and been reaped?
+    finished: bool,
+    // Resolves when the closure should be checked, if ever.
+    wakeup: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    // When to cancel the child regardless of the closure, if ever.
+    deadline: Timer,
+    // How to escalate cancellation.
+    escalation: Escalation,
+    // How the child process was cancelled, or None if it was not cancelled.
+    how_cancelled: Option<CancelSignal>,
+    // Last signal sent to the child process.
+    stopped_by: Option<CancelSignal>,
+    // Was the child process cancelled because it reached its deadline?
+    timed_out: bool,
+    // When to escalate to the next signal, if the child hasn't exited.
+    escalate: Timer
+}
+impl CancelState {
+    fn new(id: Option<u32>) -> Self {
+        Self {
+            id,
+            pidfd: id.and_then(PidFd::open),
+            group: false,
+            finished: false,
+            wakeup: None,
+            deadline: Timer::new(None),
+            escalation: Escalation::default(),
+            how_cancelled: None,
+            stopped_by: None,
+            timed_out: false,
+            escalate: Timer::new(None)
+        }
+    }
+
+    // Check whether to cancel or escalate, sending signals to the child as
+    // needed and scheduling wakeups for the deadline and the next escalation.
+    fn poll_cancel<F: FnMut() -> Option<CancelSignal>>(&mut self, check_cancel: &mut F, cx: &mut Context<'_>) {
+        // Not cancelled yet.  Check the closure and the deadline.
+        if self.stopped_by.is_none() {
+            // Polling the wakeup future schedules a wakeup when it resolves.
+            if let Some(Poll::Ready(())) = self.wakeup.as_mut().map(|wakeup| wakeup.as_mut().poll(cx)) {
+                self.wakeup = None;
+            }
+            let mut cancel_signal = check_cancel();
+            if self.deadline.poll_expired(cx) && cancel_signal.is_none() {
+                cancel_signal = Some(CancelSignal::Interrupt);
+                self.timed_out = true;
+            }
+            if let Some(cancel_signal) = cancel_signal {
+                self.how_cancelled = Some(cancel_signal);
+                self.send(cancel_signal);
+            }
+        }
+        // Escalate for as long as grace periods have run out.  Polling the
+        // timer for the current grace period schedules a wakeup at its end.
+        while self.escalate.poll_expired(cx) {
+            match self.stopped_by.and_then(|signal| self.escalation.next(signal)) {
+                Some((_, signal)) => self.send(signal),
+                None => break
+            }
+        }
+    }
+
+    // Send `signal` to the child and start the grace period for the next one.
+    fn send(&mut self, signal: CancelSignal) {
+        self.kill(signal);
+        self.stopped_by = Some(signal);
+        self.escalate = Timer::new(self.escalation.next(signal).map(|(grace, _)| Instant::now() + grace));
+    }
+
+    // Send `signal` to the child, or to its whole process group.
+    fn kill(&self, signal: CancelSignal) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return
+        };
+        let pid = match (&self.pidfd, self.group) {
+            // Signal the child through its pidfd, which fails rather than
+            // signalling another process if the child has been reaped.
+            (Some(pidfd), false) => {
+                let _ = pidfd.send_signal(signal.signum());
+                return;
+            },
+            // A negative process id signals the process group, as long as it
+            // is still ours.
+            (_, true) if self.group_running() => -(id as i32),
+            (_, true) => return,
+            (None, false) => id as i32
+        };
+        unsafe {
+            // Unsafe because we need to call libc, and because without a pidfd
+            // the process id of a lone child may be stale.
+            libc::kill(pid, signal.signum());
+        }
+    }
+
+    // Check whether the child, the leader of its process group, hasn't been
+    // reaped yet, through its pidfd if possible.  Until then its process id
+    // can't be reused, so the group id still refers to its group.
+    fn leader_running(&self) -> bool {
+        match &self.pidfd {
+            Some(pidfd) => pidfd.send_signal(0).is_ok(),
+            None => !self.finished
+        }
+    }
+
+    // Check whether the child's process group still has members.  After the
+    // leader has been reaped, e.g. by wait_with_output() while its
+    // descendants still hold the pipes, the group id can't be reused while
+    // any member is running, so probe the group with signal 0.  Once every
+    // member has exited the group id is free for another process, which is
+    // only a risk if it is reused between the probe and the signal.
+    fn group_running(&self) -> bool {
+        match self.id {
+            Some(id) => self.leader_running() || unsafe {
+                // Unsafe because we need to call libc.
+                libc::kill(-(id as i32), 0) == 0
+            },
+            None => false
+        }
+    }
+
+    // Record that the child has exited and been reaped.  Kill what remains of
+    // its process group if it was cancelled.  kill() leaves the group alone if
+    // every member has exited, since the group id may then belong to someone
+    // else.
+    fn finish(&mut self) {
+        self.finished = true;
+        if self.group && self.stopped_by.is_some() {
+            self.kill(CancelSignal::Kill);
+        }
+    }
+
+    // Exit status of the child after it exited with `status`.
+    fn exit_status(&self, status: std::process::ExitStatus) -> ExitStatus {
+        ExitStatus {
+            how_cancelled: self.how_cancelled,
+            stopped_by: self.stopped_by,
+            timed_out: self.timed_out,
+            status
+        }
+    }
+}
+impl Drop for CancelState {
+    fn drop(&mut self) {
+        // Like tokio's `kill_on_drop()`, but for the whole process group.
+        if self.group && !self.finished {
+            self.kill(CancelSignal::Kill);
+        }
+    }
+}
+impl std::fmt::Debug for CancelState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CancelState")
+            .field("id", &self.id)
+            .field("pidfd", &self.pidfd)
+            .field("group", &self.group)
+            .field("finished", &self.finished)
+            .field("how_cancelled", &self.how_cancelled)
+            .field("stopped_by", &self.stopped_by)
+            .field("timed_out", &self.timed_out)
+            .finish()
+    }
+}
+
 /// Future returned by [`CancellableChild::wait()`].  This future will finish
-/// when the child process has exited or if the child process has been
-/// cancelled, whichever comes first.
+/// when the child process has exited, either by itself or after being
+/// cancelled.
 pub struct ChildWaitFuture<'child, F: FnMut() -> Option<CancelSignal>, Fut: 'child + Future<Output = std::io::Result<std::process::ExitStatus>>> {
-    id: Option<u32>,
     check_cancel: &'child mut F,
-    how_cancelled: &'child mut Option<CancelSignal>,
+    state: &'child mut CancelState,
     exit_status: &'child mut Option<std::process::ExitStatus>,
     fut: Pin<Box<Fut>>,
 }
@@ -165,80 +501,32 @@ impl<'child, F: FnMut() -> Option<CancelSignal>, Fut: 'child + Future<Output = s
 
         // First check if the child process has already exited.
         if let Some(exit_status) = this.exit_status {
-            return Poll::Ready(Ok(ExitStatus {
-                how_cancelled: *this.how_cancelled,
-                status: Some(*exit_status)
-            }));
+            return Poll::Ready(Ok(this.state.exit_status(*exit_status)));
         }
 
         // Check if the child process is being cancelled.
-        let cancel_signal = (this.check_cancel)();
-
-        // Poll the future.
-        let poll_result = this.fut.as_mut().poll(cx);
-
-        // Deal with result.
-        match poll_result {
-            // The child has finished.  Hooray!
-            Poll::Ready(status) => match status {
-                Ok(status) => {
-                    *this.exit_status = Some(status);
-                    Poll::Ready(Ok(ExitStatus {
-                        how_cancelled: *this.how_cancelled,
-                        status: Some(status)
-                    }))
-                },
-                Err(e) => Poll::Ready(Err(e))
+        this.state.poll_cancel(this.check_cancel, cx);
+
+        // Poll the future.  The child has finished when it becomes ready,
+        // whether or not it was cancelled.
+        match this.fut.as_mut().poll(cx) {
+            Poll::Ready(Ok(status)) => {
+                *this.exit_status = Some(status);
+                this.state.finish();
+                Poll::Ready(Ok(this.state.exit_status(status)))
             },
-            // The child has not yet finished.
-            Poll::Pending => {
-                // Remember how we were cancelled.
-                *this.how_cancelled = cancel_signal;
-                match cancel_signal {
-                    // Cancel the child process and become ready immediately.
-                    Some(cancel_signal) => match cancel_signal {
-                        CancelSignal::Interrupt => {
-                            // Interrupt the child process.
-                            if let Some(id) = this.id {
-                                unsafe {
-                                    // Unsafe because we need to call libc, and
-                                    // because process id may be stale.
-                                    libc::kill(id as i32, libc::SIGINT);
-                                }
-                            }
-                            Poll::Ready(Ok(ExitStatus {
-                                how_cancelled: *this.how_cancelled,
-                                status: None
-                            }))
-                        }
-                        CancelSignal::Kill => {
-                            // Kill the child process.
-                            if let Some(id) = this.id {
-                                unsafe {
-                                    libc::kill(id as i32, libc::SIGKILL);
-                                }
-                            }
-                            Poll::Ready(Ok(ExitStatus {
-                                how_cancelled: *this.how_cancelled,
-                                status: None
-                            }))
-                        }
-                    },
-                    // Keep waiting.
-                    None => Poll::Pending
-                }
-            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending
         }
     }
 }
 
 /// Future returned by [`CancellableChild::wait_with_output()`].  This future
-/// will finish when the child process has exited or if the child process has
-/// been cancelled, whichever comes first.
+/// will finish when the child process has exited, either by itself or after
+/// being cancelled.
 pub struct ChildWaitOutputFuture<F: FnMut() -> Option<CancelSignal> + Unpin, Fut: Future<Output = std::io::Result<std::process::Output>>> {
-    id: Option<u32>,
     check_cancel: F,
-    how_cancelled: Option<CancelSignal>,
+    state: CancelState,
     fut: Pin<Box<Fut>>,
 }
 impl<F: FnMut() -> Option<CancelSignal> + Unpin, Fut: Future<Output = std::io::Result<std::process::Output>>> Future for ChildWaitOutputFuture<F, Fut> {
@@ -248,61 +536,22 @@ impl<F: FnMut() -> Option<CancelSignal> + Unpin, Fut: Future<Output = std::io::R
         let this = self.get_mut();
 
         // Check if the child process is being cancelled.
-        let cancel_signal = (this.check_cancel)();
-
-        // Poll the future.
-        let poll_result = this.fut.as_mut().poll(cx);
-
-        // Deal with result.
-        match poll_result {
-            // The child has finished.  Hooray!
-            Poll::Ready(status) => match status {
-                Ok(output) => {
-                    Poll::Ready(Ok(Output {
-                        how_cancelled: this.how_cancelled,
-                        output: Some(output)
-                    }))
-                },
-                Err(e) => Poll::Ready(Err(e))
+        this.state.poll_cancel(&mut this.check_cancel, cx);
+
+        // Poll the future.  The child has finished when it becomes ready,
+        // whether or not it was cancelled.
+        match this.fut.as_mut().poll(cx) {
+            Poll::Ready(Ok(output)) => {
+                this.state.finish();
+                Poll::Ready(Ok(Output {
+                    how_cancelled: this.state.how_cancelled,
+                    stopped_by: this.state.stopped_by,
+                    timed_out: this.state.timed_out,
+                    output
+                }))
             },
-            // The child has not yet finished.
-            Poll::Pending => {
-                // Remember how we were cancelled.
-                this.how_cancelled = cancel_signal;
-                match cancel_signal {
-                    // Cancel the child process and become ready immediately.
-                    Some(cancel_signal) => match cancel_signal {
-                        CancelSignal::Interrupt => {
-                            // Interrupt the child process.
-                            if let Some(id) = this.id {
-                                unsafe {
-                                    // Unsafe because we need to call libc, and
-                                    // because process id may be stale.
-                                    libc::kill(id as i32, libc::SIGINT);
-                                }
-                            }
-                            Poll::Ready(Ok(Output {
-                                how_cancelled: this.how_cancelled,
-                                output: None
-                            }))
-                        }
-                        CancelSignal::Kill => {
-                            // Kill the child process.
-                            if let Some(id) = this.id {
-                                unsafe {
-                                    libc::kill(id as i32, libc::SIGKILL);
-                                }
-                            }
-                            Poll::Ready(Ok(Output {
-                                how_cancelled: this.how_cancelled,
-                                output: None
-                            }))
-                        }
-                    },
-                    // Keep waiting.
-                    None => Poll::Pending
-                }
-            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending
         }
     }
 }
@@ -320,7 +569,7 @@ mod tests {
         let mut child = CancellableChild::new(child, || None);
         let status = child.wait().await.unwrap();
         assert!(status.how_cancelled.is_none());
-        assert!(status.status.unwrap().success());
+        assert!(status.status.success());
     }
 
     #[tokio::test]
@@ -333,6 +582,149 @@ mod tests {
         let elapsed = std::time::Instant::now().duration_since(now);
         assert!(elapsed < std::time::Duration::from_millis(100));
         assert!(status.how_cancelled.unwrap() == CancelSignal::Interrupt);
+        assert!(status.stopped_by.unwrap() == CancelSignal::Interrupt);
+        assert!(!status.status.success());
+    }
+
+    #[tokio::test]
+    async fn test_wait_deadline() {
+        // Run the command `sleep 10`, which never wakes the task, and cancel
+        // it at its deadline.
+        let now = std::time::Instant::now();
+        let child = Command::new("sleep").arg("10").spawn().unwrap();
+        let mut child = CancellableChild::new(child, || None)
+            .with_deadline(now + std::time::Duration::from_millis(100));
+        let status = child.wait().await.unwrap();
+        let elapsed = std::time::Instant::now().duration_since(now);
+        assert!(elapsed >= std::time::Duration::from_millis(100));
+        assert!(elapsed < std::time::Duration::from_secs(5));
+        assert!(status.timed_out);
+        assert!(status.how_cancelled.unwrap() == CancelSignal::Interrupt);
+    }
+
+    #[tokio::test]
+    async fn test_wait_escalate() {
+        // Run a shell that ignores SIGINT and SIGTERM, and cancel it once it
+        // has had time to set up its traps.
+        let now = std::time::Instant::now();
+        let child = Command::new("sh").arg("-c").arg("trap '' INT TERM; sleep 10").spawn().unwrap();
+        let mut child = CancellableChild::new(child, || None)
+            .with_deadline(now + std::time::Duration::from_millis(100))
+            .with_escalation(Escalation {
+                interrupt_grace: std::time::Duration::from_millis(100),
+                terminate_grace: std::time::Duration::from_millis(100)
+            });
+        let status = child.wait().await.unwrap();
+        let elapsed = std::time::Instant::now().duration_since(now);
+        assert!(elapsed >= std::time::Duration::from_millis(300));
+        assert!(elapsed < std::time::Duration::from_secs(5));
+        assert!(status.how_cancelled.unwrap() == CancelSignal::Interrupt);
+        assert!(status.stopped_by.unwrap() == CancelSignal::Kill);
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    // Check if process `pid` is running, i.e. exists and isn't a zombie.
+    fn is_running(pid: i32) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !matches!(stat.rsplit(')').next().and_then(|rest| rest.split_whitespace().next()), Some("Z") | Some("X")),
+            Err(_) => false
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_group() {
+        use tokio::io::AsyncBufReadExt;
+        // Run a shell with a background grandchild, which ignores SIGINT, and
+        // cancel the shell once we know the grandchild's pid.
+        let ready = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let cancel = {
+            let ready = ready.clone();
+            move || match ready.load(std::sync::atomic::Ordering::Relaxed) {
+                true => Some(CancelSignal::Interrupt),
+                false => None
+            }
+        };
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("sleep 30 & echo $!; wait").stdout(std::process::Stdio::piped());
+        let mut child = CancellableChild::spawn_group(&mut command, cancel).unwrap()
+            .with_escalation(Escalation {
+                interrupt_grace: std::time::Duration::from_millis(100),
+                terminate_grace: std::time::Duration::from_millis(100)
+            });
+        let mut line = String::new();
+        tokio::io::BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).await.unwrap();
+        let grandchild: i32 = line.trim().parse().unwrap();
+        assert!(is_running(grandchild));
+        ready.store(true, std::sync::atomic::Ordering::Relaxed);
+        let status = child.wait().await.unwrap();
+        assert!(status.how_cancelled.unwrap() == CancelSignal::Interrupt);
+        // The grandchild is killed along with the shell.
+        let now = std::time::Instant::now();
+        while is_running(grandchild) && now.elapsed() < std::time::Duration::from_secs(5) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!is_running(grandchild));
+
+        // Dropping a child kills its whole group.
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("sleep 30 & echo $!; wait").stdout(std::process::Stdio::piped());
+        let mut child = CancellableChild::spawn_group(&mut command, || None).unwrap();
+        let mut line = String::new();
+        tokio::io::BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).await.unwrap();
+        let grandchild: i32 = line.trim().parse().unwrap();
+        drop(child);
+        let now = std::time::Instant::now();
+        while is_running(grandchild) && now.elapsed() < std::time::Duration::from_secs(5) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!is_running(grandchild));
+    }
+
+    #[tokio::test]
+    async fn test_wait_output_group() {
+        // The shell dies of SIGINT and is reaped while its grandchild, which
+        // ignores SIGINT and SIGTERM, still holds the pipes.  The grandchild
+        // must still be sent SIGKILL for the pipes to close.
+        let now = std::time::Instant::now();
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("(trap '' TERM; exec sleep 20) & wait")
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+        let child = CancellableChild::spawn_group(&mut command, || None).unwrap()
+            .with_deadline(now + std::time::Duration::from_millis(300))
+            .with_escalation(Escalation {
+                interrupt_grace: std::time::Duration::from_millis(300),
+                terminate_grace: std::time::Duration::from_millis(300)
+            });
+        let output = tokio::time::timeout(std::time::Duration::from_secs(5), child.wait_with_output()).await
+            .expect("grandchild wasn't killed")
+            .unwrap();
+        assert!(output.timed_out);
+        assert!(output.stopped_by.unwrap() == CancelSignal::Kill);
+    }
+
+    #[tokio::test]
+    async fn test_kill_group_pidfd() {
+        let mut command = Command::new("sleep");
+        command.arg("20");
+        let child = CancellableChild::spawn_group(&mut command, || None).unwrap();
+        // Nothing to test without pidfd support.
+        if child.state.pidfd.is_none() {
+            return;
+        }
+        assert!(child.state.leader_running());
+        assert!(child.state.group_running());
+        // Reap the leader behind the state's back.  Only the pidfd can tell
+        // that it is gone, and then the group is left alone.
+        let pid = child.id().unwrap() as libc::pid_t;
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+        assert!(!child.state.finished);
+        assert!(!child.state.leader_running());
+        assert!(!child.state.group_running());
+        child.state.kill(CancelSignal::Kill);
     }
 
     #[tokio::test]
@@ -346,7 +738,7 @@ mod tests {
         let child = CancellableChild::new(child, || None);
         let output = child.wait_with_output().await.unwrap();
         assert!(output.how_cancelled.is_none());
-        let output = output.output.unwrap();
+        let output = output.output;
         assert!(output.status.success());
         assert!(std::str::from_utf8(&output.stdout).unwrap() == "hello\n");
     }
@@ -0,0 +1,84 @@
+//! Process file descriptors, which refer to one process and can't be
+//! confused with a later process that reuses its process id.  Only available
+//! on Linux 5.3 and newer.
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+/// File descriptor referring to a process, see `pidfd_open(2)`.
+#[derive(Debug)]
+pub struct PidFd {
+    #[cfg(target_os = "linux")]
+    fd: OwnedFd
+}
+impl PidFd {
+    /// Open a file descriptor for the process `pid`, which must be a child of
+    /// the current process that hasn't been reaped yet.  Returns `None` if
+    /// pidfds aren't supported, e.g. on kernels older than 5.3 or in a
+    /// sandbox that blocks the system call.
+    #[cfg(target_os = "linux")]
+    pub fn open(pid: u32) -> Option<Self> {
+        // Safe because pidfd_open() has no side effects besides creating the
+        // file descriptor, which we take ownership of.
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0 as libc::c_uint) };
+        match fd >= 0 {
+            true => Some(Self { fd: unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) } }),
+            false => None
+        }
+    }
+
+    /// Open a file descriptor for the process `pid`.  Always returns `None`
+    /// on platforms other than Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_pid: u32) -> Option<Self> {
+        None
+    }
+
+    /// Send signal `signum` to the process, see `pidfd_send_signal(2)`.  A
+    /// signal of 0 checks that the process exists.  Fails with `ESRCH` once
+    /// the process has been reaped, instead of signalling whichever process
+    /// now has its process id.
+    #[cfg(target_os = "linux")]
+    pub fn send_signal(&self, signum: libc::c_int) -> std::io::Result<()> {
+        // Safe because the file descriptor is valid for the lifetime of self.
+        let res = unsafe {
+            libc::syscall(libc::SYS_pidfd_send_signal, self.fd.as_raw_fd(), signum, std::ptr::null::<libc::siginfo_t>(), 0 as libc::c_uint)
+        };
+        match res {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error())
+        }
+    }
+
+    /// Send signal `signum` to the process.  Never called on platforms other
+    /// than Linux, where [`PidFd::open()`] always returns `None`.
+    #[cfg(not(target_os = "linux"))]
+    pub fn send_signal(&self, _signum: libc::c_int) -> std::io::Result<()> {
+        Err(std::io::Error::from_raw_os_error(libc::ENOSYS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pidfd() {
+        let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+        let pidfd = match PidFd::open(child.id()) {
+            Some(pidfd) => pidfd,
+            // Nothing to test without pidfd support.
+            None => {
+                child.kill().unwrap();
+                child.wait().unwrap();
+                return;
+            }
+        };
+        pidfd.send_signal(0).unwrap();
+        pidfd.send_signal(libc::SIGKILL).unwrap();
+        child.wait().unwrap();
+        // The process is gone, even if its pid has been reused.
+        let err = pidfd.send_signal(0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+    }
+}
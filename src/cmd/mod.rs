@@ -1,6 +1,8 @@
 //! Module for command line parsing.  Uses the
 //! [structopt](https://docs.rs/structopt) crate.
 
+use mriqc1::bids::{EntityFilter, LinkStrategy, ParticipantLabel};
+use mriqc1::resources::MemoryLimit;
 use std::ffi::OsString;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -11,18 +13,56 @@ use structopt::StructOpt;
 /// parallel instances to throttle system resource usage.
 pub struct Opts {
     /// BIDS directory containing data.
-    #[structopt(long="bids-dir", parse(from_os_str))]
-    pub bids_dir: PathBuf,
+    #[structopt(long="bids-dir", parse(from_os_str), required_unless = "clean")]
+    pub bids_dir: Option<PathBuf>,
 
     /// Directory for output files.
-    #[structopt(long="out-dir", parse(from_os_str))]
-    pub out_dir: PathBuf,
+    #[structopt(long="out-dir", parse(from_os_str), required_unless = "clean")]
+    pub out_dir: Option<PathBuf>,
 
-    /// Participant label(s).
-    #[structopt(long = "participant-label", required = true)]
-    pub participant_labels: Vec<String>,
+    /// Instead of running mriqc, remove temporary directories in the working
+    /// directory left behind by instances of mriqc1 that were killed.
+    /// Directories still in use, or created on another host, are left alone.
+    #[structopt(long)]
+    pub clean: bool,
+
+    /// Participant label(s), with or without the "sub-" prefix.  If omitted,
+    /// all participants found in the BIDS directory are processed.
+    #[structopt(long = "participant-label")]
+    pub participant_labels: Vec<ParticipantLabel>,
+
+    /// Read additional participant labels from a newline-delimited file, or
+    /// from standard input if the file is `-`.
+    #[structopt(long = "participant-file", name = "file", parse(from_os_str))]
+    pub participant_file: Option<PathBuf>,
+
+    /// Only process participants whose row in the BIDS directory's
+    /// participants.tsv has the given value in the given column, e.g.
+    /// `--where group=control`.  May be repeated, in which case participants
+    /// must match all conditions.
+    #[structopt(long = "where", name = "column=value", number_of_values = 1, parse(try_from_str = parse_where))]
+    pub where_conditions: Vec<(String, String)>,
+
+    /// Run a separate instance of mriqc for each of a participant's sessions
+    /// instead of one instance per participant.
+    #[structopt(long = "per-session")]
+    pub per_session: bool,
+
+    /// Only give mriqc the scans whose BIDS entities match, e.g.
+    /// `task=rest,run=1`, along with their sidecars and fieldmaps.  May be
+    /// repeated to allow more values, e.g. `--include-entities task=rest
+    /// --include-entities task=nback`.
+    #[structopt(long = "include-entities", name = "key=value,...", number_of_values = 1)]
+    pub include_entities: Vec<EntityFilter>,
 
-    /// Number of participants to run in parallel.
+    /// Number of participants (or sessions with --per-session) to process with
+    /// each instance of mriqc.  Larger batches spend less time starting mriqc
+    /// but use more memory per instance.
+    #[structopt(long = "batch-size", name = "K", default_value = "1")]
+    pub batch_size: usize,
+
+    /// Maximum number of participants to run in parallel.  Fewer run while
+    /// memory is short, see --mem-per-instance and --max-mem-pressure.
     #[structopt(short = "n", name="parallel", default_value = "1")]
     pub n_par: usize,
 
@@ -30,16 +70,86 @@ pub struct Opts {
     #[structopt(short = "w", long = "work-dir", parse(from_os_str))]
     pub work_dir: Option<PathBuf>,
 
-    /// Skip participants for whom any data is already present in the output
-    /// directory.
+    /// Skip participants (or sessions with --per-session) whose scans all have
+    /// IQMs and reports in the output directory.  Participants with missing
+    /// outputs are rerun.
     #[structopt(long)]
     pub resume: bool,
 
     /// Cancel a participant's mriqc process if it runs longer than this many
-    /// minutes
+    /// minutes.  mriqc is sent SIGINT, then SIGTERM if it is still running 30
+    /// seconds later, and finally SIGKILL after another 10 seconds
     #[structopt(long, name = "minutes", parse(try_from_str = parse_minutes))]
     pub timeout: Option<std::time::Duration>,
 
+    /// Limit the memory used by each participant's mriqc process, e.g. `16G`.
+    /// Each process runs in its own cgroup with this memory.max if the cgroup
+    /// v2 memory controller is delegated to mriqc1, otherwise each of its
+    /// processes' virtual memory is limited with RLIMIT_AS.  Participants
+    /// that run out of memory are reported as such.
+    #[structopt(long = "mem-limit", name = "size")]
+    pub mem_limit: Option<MemoryLimit>,
+
+    /// Memory each participant's mriqc process is expected to use, e.g. `8G`,
+    /// defaults to --mem-limit.  Another participant is only started when
+    /// the system has this much memory available for it and for each
+    /// participant started within the last minute.
+    #[structopt(long = "mem-per-instance", name = "estimate")]
+    pub mem_per_instance: Option<MemoryLimit>,
+
+    /// Don't start another participant while the system has less than this
+    /// much memory available, whether or not --mem-per-instance is given.
+    #[structopt(long = "min-mem-available", name = "min-size", default_value = "2G")]
+    pub min_mem_available: MemoryLimit,
+
+    /// Don't start another participant while tasks were stalled waiting for
+    /// memory more than this percentage of the last 10 seconds, or while the
+    /// system is filling its swap.
+    #[structopt(long = "max-mem-pressure", name = "percent", default_value = "10")]
+    pub max_mem_pressure: f64,
+
+    /// Don't shadow entries in the root of the BIDS directory whose names
+    /// match this pattern, e.g. `phenotype` or `*.tsv`.  The wildcard `*`
+    /// matches any characters and `?` matches any one character.  May be
+    /// repeated.
+    #[structopt(long = "shadow-exclude", name = "pattern", number_of_values = 1)]
+    pub shadow_exclude: Vec<String>,
+
+    /// How to link data into each instance of mriqc's shadow copy of the BIDS
+    /// directory.  Use `hardlink`, `reflink`, or `copy` if symlinks don't work,
+    /// e.g. inside a Singularity container.  Hard links require the working
+    /// directory to be on the same filesystem as the BIDS directory.
+    #[structopt(long = "link-mode", name = "mode", default_value = "symlink", possible_values = &["symlink", "hardlink", "reflink", "copy"])]
+    pub link_mode: LinkStrategy,
+
+    /// Copy each participant's data into mriqc's temporary directory before
+    /// running mriqc, e.g. to stage data from a slow network filesystem onto
+    /// local scratch given by --work-dir.  The next participant is staged while
+    /// the current ones run.
+    #[structopt(long)]
+    pub stage: bool,
+
+    /// Keep the temporary directory of participants whose mriqc process fails
+    /// or times out, for debugging.  It is renamed to
+    /// `mriqc1-keep-sub-<label>` in the working directory, and its path is
+    /// included in the warning.
+    #[structopt(long = "keep-work-on-failure")]
+    pub keep_work_on_failure: bool,
+
+    /// Keep the temporary directory of every participant, like
+    /// --keep-work-on-failure.
+    #[structopt(long = "keep-work")]
+    pub keep_work: bool,
+
+    /// Reuse the directory `mriqc1-sub-<label>` in the working directory for
+    /// each participant instead of a new temporary directory, so that a rerun
+    /// of a participant that failed can skip the steps that already finished.
+    /// The directory is locked while in use, kept if mriqc fails or is
+    /// cancelled, and removed when mriqc succeeds (unless --keep-work is
+    /// given).  Can't be combined with a --batch-size greater than 1.
+    #[structopt(long = "reuse-work")]
+    pub reuse_work: bool,
+
     /// Location of mriqc binary.
     #[structopt(long = "mriqc", default_value = "mriqc", env = "MRIQC", parse(from_os_str))]
     pub mriqc: PathBuf,
@@ -49,7 +159,9 @@ pub struct Opts {
     pub quiet: bool,
 
     /// Convert warnings about failure to process a participant to errors and
-    /// exit on the first error.  This does not apply to timeout warnings.
+    /// exit on the first error.  Also exit before processing any participants
+    /// if some of the given participants are missing or duplicated.  This does
+    /// not apply to timeout warnings.
     #[structopt(long)]
     pub werror: bool,
 
@@ -62,6 +174,14 @@ fn parse_minutes(minutes: &str) -> Result<std::time::Duration, std::num::ParseIn
     Ok(std::time::Duration::from_secs(minutes.parse::<u64>()? * 60))
 }
 
+// Helper function to parse a `column=value` condition.
+fn parse_where(condition: &str) -> Result<(String, String), String> {
+    match condition.split_once('=') {
+        Some((column, value)) if !column.is_empty() => Ok((column.into(), value.into())),
+        _ => Err(format!("expected column=value, got \"{}\"", condition))
+    }
+}
+
 // Custom type for command line parsing errors.
 mod error;
 pub use error::OptsError;
@@ -77,3 +197,18 @@ impl Opts {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_where() {
+        assert_eq!(parse_where("group=control"), Ok(("group".into(), "control".into())));
+        assert_eq!(parse_where("group="), Ok(("group".into(), "".into())));
+        assert_eq!(parse_where("note=a=b"), Ok(("note".into(), "a=b".into())));
+        assert!(parse_where("=control").is_err());
+        assert!(parse_where("group").is_err());
+        assert!(parse_where("").is_err());
+    }
+}
@@ -1,3 +1,9 @@
+pub mod admission;
 pub mod bids;
 pub mod cancellable_process;
+pub mod logs;
 pub mod mriqc;
+mod proc_stat;
+pub mod resources;
+pub mod usage;
+pub mod work_dir;
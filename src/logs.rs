@@ -0,0 +1,176 @@
+//! Collect nipype crash files and mriqc's logs after mriqc fails.
+//!
+//! When a step of mriqc's workflow fails, nipype writes a crash file such as
+//! `crash-20210101-120000-user-ReadT1w.a0-0123abcd.txt` describing the step's
+//! inputs and the traceback.  Depending on mriqc's version these end up in
+//! mriqc's working directory, which is normally removed when mriqc finishes,
+//! or in the output directory's `logs` folder alongside mriqc's own logs,
+//! which is shared by every instance of mriqc writing to the same output
+//! directory.  [`collect_logs()`] gathers the files belonging to one instance
+//! of mriqc into a folder of their own.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Name of the folder within the output directory's `logs` folder where logs
+/// are collected, i.e. `<out_dir>/logs/mriqc1`.
+pub const LOGS_DIR: &str = "mriqc1";
+
+/// Where to find an instance of mriqc's logs, see [`collect_logs()`].
+#[derive(Debug, Clone)]
+pub struct LogSources<'a> {
+    /// mriqc's working directory.
+    pub work_dir: &'a Path,
+    /// mriqc's output directory.
+    pub out_dir: &'a Path,
+    /// When the instance of mriqc started.  Older files in the output
+    /// directory are ignored.
+    pub since: SystemTime,
+    /// Captured output of mriqc on stdout, if any.
+    pub stdout: Option<&'a [u8]>,
+    /// Captured output of mriqc on stderr, if any.
+    pub stderr: Option<&'a [u8]>,
+}
+
+/// Copy an instance of mriqc's crash files and logs into `dest`, e.g.
+/// `<out_dir>/logs/mriqc1/sub-01`, which is created if needed.  Collects:
+///
+/// * nipype crash files (`crash-*`) anywhere in mriqc's working directory,
+/// * text files in `<out_dir>/logs` (crash files, `*.log`, `*.toml`) written
+///   since mriqc started that mention mriqc's working directory, so that the
+///   logs of other instances of mriqc sharing the output directory are left
+///   out, and
+/// * mriqc's captured stdout and stderr as `stdout.log` and `stderr.log`.
+///
+/// Symbolic links are not followed.  Returns the paths of the collected files
+/// in `dest`.
+pub async fn collect_logs(sources: &LogSources<'_>, dest: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for path in walk(sources.work_dir, None).await? {
+        if is_crash_file(&path) {
+            found.push(path);
+        }
+    }
+    let out_logs = sources.out_dir.join("logs");
+    let marker = sources.work_dir.to_string_lossy();
+    if tokio::fs::symlink_metadata(&out_logs).await.is_ok() {
+        for path in walk(&out_logs, Some(&out_logs.join(LOGS_DIR))).await? {
+            if is_text_log(&path) && modified_since(&path, sources.since).await && mentions(&path, &marker).await {
+                found.push(path);
+            }
+        }
+    }
+
+    let mut collected = Vec::new();
+    if found.is_empty() && sources.stdout.is_none() && sources.stderr.is_none() {
+        return Ok(collected);
+    }
+    tokio::fs::create_dir_all(dest).await?;
+    for path in found {
+        // Crash file names are unique, so they can all go in one folder.
+        if let Some(name) = path.file_name() {
+            let dst = dest.join(name);
+            tokio::fs::copy(&path, &dst).await?;
+            collected.push(dst);
+        }
+    }
+    for (name, output) in [("stdout.log", sources.stdout), ("stderr.log", sources.stderr)] {
+        if let Some(output) = output {
+            let dst = dest.join(name);
+            tokio::fs::write(&dst, output).await?;
+            collected.push(dst);
+        }
+    }
+    Ok(collected)
+}
+
+// List the regular files in the tree at `root` without following symbolic
+// links, skipping the directory `skip` if given.
+async fn walk(root: &Path, skip: Option<&Path>) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() && Some(path.as_path()) != skip {
+                pending.push(path);
+            } else if file_type.is_file() {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Check if `path` is a nipype crash file.
+fn is_crash_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("crash-"))
+        .unwrap_or(false)
+}
+
+// Check if `path` is a log we can search for the working directory.  Crash
+// files in nipype's compressed pickle format (`.pklz`) are only collected
+// from the working directory.
+fn is_text_log(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("log") | Some("toml") => true,
+        Some("txt") => is_crash_file(path),
+        _ => false
+    }
+}
+
+// Check if the file at `path` was modified at or after `since`.
+async fn modified_since(path: &Path, since: SystemTime) -> bool {
+    match tokio::fs::metadata(path).await.and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified >= since,
+        Err(_) => false
+    }
+}
+
+// Check if the file at `path` contains `marker`.
+async fn mentions(path: &Path, marker: &str) -> bool {
+    match tokio::fs::read(path).await {
+        Ok(contents) => String::from_utf8_lossy(&contents).contains(marker),
+        Err(_) => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_collect_logs() {
+        let root = tempfile::tempdir().unwrap();
+        let work_dir = root.path().join("work");
+        let out_dir = root.path().join("out");
+        let since = SystemTime::now() - std::time::Duration::from_secs(60);
+        std::fs::create_dir_all(work_dir.join("workflow/node")).unwrap();
+        std::fs::create_dir_all(out_dir.join("logs")).unwrap();
+        std::fs::write(work_dir.join("workflow/node/crash-1-node.pklz"), "").unwrap();
+        std::fs::write(work_dir.join("workflow/node/result_node.pklz"), "").unwrap();
+        let ours = format!("input: {}/bids/sub-01_T1w.nii.gz", work_dir.to_string_lossy());
+        std::fs::write(out_dir.join("logs/crash-2-node.txt"), &ours).unwrap();
+        std::fs::write(out_dir.join("logs/crash-3-node.txt"), "input: /elsewhere/sub-02_T1w.nii.gz").unwrap();
+        std::fs::write(out_dir.join("logs/mriqc.log"), &ours).unwrap();
+        let sources = LogSources {
+            work_dir: &work_dir,
+            out_dir: &out_dir,
+            since,
+            stdout: None,
+            stderr: Some(b"Traceback")
+        };
+        let dest = out_dir.join("logs").join(LOGS_DIR).join("sub-01");
+        let collected = collect_logs(&sources, &dest).await.unwrap();
+        let mut names: Vec<_> = collected.iter().map(|path| path.file_name().unwrap().to_str().unwrap()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["crash-1-node.pklz", "crash-2-node.txt", "mriqc.log", "stderr.log"]);
+        // Collecting again doesn't pick up the collected copies.
+        assert_eq!(collect_logs(&sources, &dest).await.unwrap().len(), 4);
+    }
+}
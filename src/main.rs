@@ -1,46 +1,116 @@
 use anyhow::{bail, Context, Result};
 use futures_util::stream::{StreamExt, TryStreamExt};
 use indicatif::{MultiProgress, ProgressDrawTarget, ProgressBar, ProgressStyle};
+use mriqc1::admission::{Admission, AdmissionOptions};
+use mriqc1::bids::{discover_participants, discover_sessions, validate_participants, EntityFilter, ParticipantLabel, ParticipantsTsv, ShadowBidsOptions};
 use mriqc1::cancellable_process::CancelSignal;
-use mriqc1::mriqc::{MriqcError, Mriqc1Options, Mriqc1Process};
+use mriqc1::mriqc::{check_outputs, matching_scans, ExpectedOutputs, KeepWork, Modality, MriqcError, Mriqc1Options, Mriqc1Workspace};
+use mriqc1::resources::{MemoryCap, MemoryLimit};
+use mriqc1::usage::{UsageLog, UsageMonitor};
+use mriqc1::work_dir::find_stale;
 use std::ffi::{OsStr, OsString};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 
 mod cmd;
 mod indicatif_progress_stream;
 use indicatif_progress_stream::ProgressStream;
+mod unit;
+use unit::{Batch, Batcher, Checked, Outcome, PendingUnit, Prepared, WorkUnit};
+
+// Options passed to each instance of mriqc.
+struct MriqcOptions {
+    bids_dir: PathBuf,
+    out_dir: PathBuf,
+    mriqc: PathBuf,
+    work_dir: Option<PathBuf>,
+    extra_args: Vec<OsString>,
+    shadow_options: ShadowBidsOptions,
+    // Modalities requested with -m, or None if we don't recognize them
+    modalities: Option<Vec<Modality>>,
+    // Only shadow participants' files matching this filter
+    entity_filter: Option<EntityFilter>,
+    // Copy participants' data into the temporary directory
+    stage: bool,
+    // When to keep the temporary directory
+    keep_work: KeepWork,
+    // Reuse a stable temporary directory for each participant
+    reuse_work: bool,
+    // Limit each instance of mriqc's memory
+    mem_limit: Option<MemoryLimit>
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments and destructure.
     let cmd_opts = cmd::Opts::from_args()?;
     let cmd_opts_quiet = cmd_opts.quiet;
+    // Clean up after killed instances of mriqc1 instead of running mriqc.
+    if cmd_opts.clean {
+        let work_dir = cmd_opts.work_dir.unwrap_or_else(std::env::temp_dir);
+        return clean_work_dir(&work_dir, cmd_opts_quiet).await;
+    }
     let cmd_opts_n_par = cmd_opts.n_par;
     let cmd_opts_resume = cmd_opts.resume;
     let cmd_opts_timeout = cmd_opts.timeout;
     let cmd_opts_werror = cmd_opts.werror;
-    let participants = cmd_opts.participant_labels;
-    struct MriqcOptions { // pptions passed to each instance of mriqc
-        bids_dir: PathBuf,
-        out_dir: PathBuf,
-        mriqc: PathBuf,
-        work_dir: Option<PathBuf>,
-        extra_args: Vec<OsString>
+    let cmd_opts_per_session = cmd_opts.per_session;
+    let cmd_opts_batch_size = cmd_opts.batch_size;
+    // A batch's reused directory is named after its first participant, so a
+    // rerun wouldn't find it if the batches came out differently.
+    if cmd_opts.reuse_work && cmd_opts_batch_size > 1 {
+        bail!("--reuse-work can't be combined with a --batch-size greater than 1.");
     }
+    // Start instances of mriqc only when there is memory for them.
+    let admission = Arc::new(Admission::new(AdmissionOptions {
+        per_instance: cmd_opts.mem_per_instance.or(cmd_opts.mem_limit),
+        min_available: cmd_opts.min_mem_available,
+        max_pressure: cmd_opts.max_mem_pressure,
+        ..Default::default()
+    }));
+    let mut participants = cmd_opts.participant_labels;
+    let participant_file = cmd_opts.participant_file;
+    let where_conditions = cmd_opts.where_conditions;
     let mriqc_options = Arc::new(MriqcOptions {
-        bids_dir: cmd_opts.bids_dir,
-        out_dir: cmd_opts.out_dir,
+        bids_dir: cmd_opts.bids_dir.context("--bids-dir is required")?,
+        out_dir: cmd_opts.out_dir.context("--out-dir is required")?,
         mriqc: cmd_opts.mriqc,
         work_dir: match cmd_opts.work_dir {
-            Some(work_dir) => Some(work_dir.into()),
+            Some(work_dir) => Some(work_dir),
             None => Some(std::env::temp_dir())
         },
-        extra_args: cmd_opts.extra_args
+        modalities: Modality::from_args(&cmd_opts.extra_args),
+        extra_args: cmd_opts.extra_args,
+        shadow_options: ShadowBidsOptions {
+            exclude: cmd_opts.shadow_exclude,
+            link_strategy: cmd_opts.link_mode,
+            ..Default::default()
+        },
+        entity_filter: match cmd_opts.include_entities.is_empty() {
+            true => None,
+            false => {
+                let mut entity_filter = EntityFilter::new();
+                for filter in cmd_opts.include_entities {
+                    entity_filter.extend(filter);
+                }
+                Some(entity_filter)
+            }
+        },
+        stage: cmd_opts.stage,
+        keep_work: match (cmd_opts.keep_work, cmd_opts.keep_work_on_failure) {
+            (true, _) => KeepWork::Always,
+            (false, true) => KeepWork::OnFailure,
+            (false, false) => KeepWork::Never
+        },
+        reuse_work: cmd_opts.reuse_work,
+        mem_limit: cmd_opts.mem_limit
     });
 
+    // Record each participant's peak resource usage in the output directory.
+    let usage_log = Arc::new(UsageLog::new(&mriqc_options.out_dir));
+
     // Make sure provided paths are valid, readable/writable directories.
     // Can we read from the BIDS directory?
     let _ = tokio::fs::read_dir(&mriqc_options.bids_dir).await.context(format!("Couldn't read BIDS directory: {}", mriqc_options.bids_dir.to_string_lossy()))?;
@@ -51,6 +121,95 @@ async fn main() -> Result<()> {
         let _ = tempfile::tempdir_in(work_dir).context(format!("Working directory is not writable: {}", work_dir.to_string_lossy()))?;
     }
 
+    // Set up cgroups to enforce the memory limit, and let the user know if
+    // that isn't possible.
+    if mriqc_options.mem_limit.is_some() && !MemoryCap::setup() && !cmd_opts_quiet {
+        let mut stderr = tokio::io::stderr();
+        stderr.write_all(b"Notice: cgroup v2 memory controller is not available, limiting the virtual memory of each of mriqc's processes with RLIMIT_AS instead.\n").await?;
+    }
+
+    // Append participants listed in a file, if any.
+    if let Some(participant_file) = participant_file {
+        participants.extend(read_participant_file(&participant_file).await?);
+    }
+    // If no participants were given on the command line then process every
+    // participant in the BIDS directory.
+    let mut participants = match participants.is_empty() {
+        // Make sure every participant exists before we start processing.
+        false => {
+            let validation = validate_participants(&mriqc_options.bids_dir, &participants).await?;
+            if !validation.is_ok() {
+                match cmd_opts_werror {
+                    true => bail!("{}", validation),
+                    false => if !cmd_opts_quiet {
+                        let mut stderr = tokio::io::stderr();
+                        stderr.write_all(format!("Warning: {}\n", validation).as_bytes()).await?;
+                    }
+                }
+            }
+            if validation.found.is_empty() {
+                bail!("None of the given participants were found in BIDS directory: {}", mriqc_options.bids_dir.to_string_lossy());
+            }
+            validation.found
+        },
+        true => {
+            let participants = discover_participants(&mriqc_options.bids_dir).await?;
+            if participants.is_empty() {
+                bail!("No participants found in BIDS directory: {}", mriqc_options.bids_dir.to_string_lossy());
+            }
+            if !cmd_opts_quiet {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(format!("Found {} participants in BIDS directory.\n", participants.len()).as_bytes()).await?;
+            }
+            participants
+        }
+    };
+    // Only keep participants matching the conditions in participants.tsv.
+    if !where_conditions.is_empty() {
+        let selected = ParticipantsTsv::read(&mriqc_options.bids_dir).await?.filter(&where_conditions)?;
+        participants.retain(|participant| selected.contains(participant));
+        if participants.is_empty() {
+            bail!("No participants match the conditions given with --where.");
+        }
+        if !cmd_opts_quiet {
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(format!("Selected {} participants from participants.tsv.\n", participants.len()).as_bytes()).await?;
+        }
+    }
+
+    // Divide participants into units of work, one for each instance of mriqc.
+    let units: Vec<WorkUnit> = match cmd_opts_per_session {
+        // One unit per participant.
+        false => participants.into_iter().map(|participant|
+            WorkUnit { participant, session: None }
+        ).collect(),
+        // One unit per session of each participant.
+        true => {
+            let mut units = Vec::new();
+            for participant in participants {
+                // Participants that don't exist or don't have any sessions are
+                // processed as a whole.  Missing participants will be reported
+                // when it is their turn to be processed.
+                let sessions = discover_sessions(mriqc_options.bids_dir.join(participant.path())).await.unwrap_or_default();
+                match sessions.is_empty() {
+                    true => units.push(WorkUnit { participant, session: None }),
+                    false => units.extend(sessions.into_iter().map(|session|
+                        WorkUnit { participant: participant.clone(), session: Some(session) }
+                    ))
+                }
+            }
+            if !cmd_opts_quiet {
+                let mut stdout = tokio::io::stdout();
+                stdout.write_all(format!("Found {} sessions to process.\n", units.len()).as_bytes()).await?;
+            }
+            units
+        }
+    };
+    let unit_noun = match cmd_opts_per_session {
+        true => "sessions",
+        false => "participants"
+    };
+
     // Set up a multi-progress bar.
     // The bar is stored in an `Arc` to facilitate sharing between threads.
     let multibar = match cmd_opts_quiet {
@@ -69,13 +228,12 @@ async fn main() -> Result<()> {
                 stdout.write_all(b"Running mriqc, this could take a long time. Press Ctrl+C to cancel.\n").await?;
             }
             // Configure progress bar.
-            let pb = ProgressBar::new(participants.len() as u64)
+            ProgressBar::new(units.len() as u64)
             .with_style(
                 ProgressStyle::default_bar()
-		        .template("({pos}/{len} participants): {elapsed} [{wide_bar}] {eta}")
+		        .template(&format!("({{pos}}/{{len}} {}): {{elapsed}} [{{wide_bar}}] {{eta}}", unit_noun))
 		        .progress_chars("=> ")
-            );
-            pb
+            )
         }
     };
     // Add this indicator to the multibar.
@@ -97,8 +255,11 @@ async fn main() -> Result<()> {
         },
     };
 
-    // Install signal handler.  Set atomic flag to true if we are interrupted.
+    // Install signal handler.  Set atomic flag to true if we are interrupted,
+    // and notify running instances of mriqc, which don't see Ctrl+C from the
+    // terminal because they run in their own process groups.
     let interrupted = Arc::new(AtomicBool::new(false));
+    let (interrupt_tx, interrupt_rx) = tokio::sync::watch::channel(());
     {
         let interrupted = interrupted.clone();
         tokio::spawn(async move {
@@ -109,80 +270,183 @@ async fn main() -> Result<()> {
 
             // Received interrupt signal, set global interrupt flag.
             interrupted.store(true, Ordering::Relaxed);
+            let _ = interrupt_tx.send(());
         });
     }
 
-    // Iterate over stream of participants provded on the command line.
-    futures_util::stream::iter(participants)
+    // Check which units of work need mriqc to run, and divide those that do
+    // into batches for each instance of mriqc as they are checked, so that
+    // mriqc can start before every unit has been checked.
+    futures_util::stream::iter(units)
+        .map(|unit| check_unit(unit, mriqc_options.clone(), cmd_opts_resume, cmd_opts_quiet))
+        .buffered(cmd_opts_n_par)
+        // Mark the end of the units, so that batches that aren't full are
+        // started too.
+        .map(Some)
+        .chain(futures_util::stream::once(async { None }))
+        .scan(Batcher::new(cmd_opts_batch_size), |batcher, checked| {
+            let jobs: Vec<Result<Prepared, Batch>> = match checked {
+                Some(Ok(Checked::Pending(unit))) => batcher.push(unit).into_iter().map(Err).collect(),
+                Some(Ok(Checked::Done(outcome))) => vec![Ok(Prepared::Done(vec![Ok(outcome)]))],
+                Some(Err(error)) => vec![Ok(Prepared::Done(vec![Err(error)]))],
+                None => batcher.finish().into_iter().map(Err).collect()
+            };
+            futures_util::future::ready(Some(jobs))
+        })
+        .flat_map(futures_util::stream::iter)
         // Cancel the stream if we get interrupted.
         .take_while(|_| {
             let interrupted = interrupted.clone();
             async move { !interrupted.load(Ordering::Relaxed) }
         })
+        // Prepare each batch's workspace, e.g. stage its data, in a background
+        // task so that the next batch is prepared while the current ones run.
+        .map(|job| {
+            let mriqc_options = mriqc_options.clone();
+            tokio::spawn(async move {
+                let batch = match job {
+                    Ok(done) => return done,
+                    Err(batch) => batch
+                };
+                let options = Mriqc1Options {
+                    bids_dir: &mriqc_options.bids_dir,
+                    out_dir: &mriqc_options.out_dir,
+                    mriqc: Some(&mriqc_options.mriqc),
+                    work_dir: mriqc_options.work_dir.as_deref(),
+                    extra_args: mriqc_options.extra_args.iter().map(|s| s as &OsStr).collect(),
+                    participants: batch.participants(),
+                    session: batch.session(),
+                    shadow_options: Some(&mriqc_options.shadow_options),
+                    entity_filter: mriqc_options.entity_filter.as_ref(),
+                    stage: mriqc_options.stage,
+                    keep_work: mriqc_options.keep_work,
+                    reuse_work: mriqc_options.reuse_work,
+                    mem_limit: mriqc_options.mem_limit
+                };
+                match Mriqc1Workspace::new(options).await {
+                    Ok(workspace) => Prepared::Ready(batch, Box::new(workspace)),
+                    Err(error) => Prepared::Done(batch.failed(error).await)
+                }
+            })
+        })
+        // Keep one batch prepared ahead of the ones that are running.  The
+        // background task keeps working even while this stream isn't polled.
+        .buffered(2)
+        // Don't start prepared batches if we got interrupted in the meantime.
+        .take_while(|_| {
+            let interrupted = interrupted.clone();
+            async move { !interrupted.load(Ordering::Relaxed) }
+        })
         // Perform the actual mriqc processing.
-        .map(|participant| {
-            // Set up a progress bar for this participant.
-            let participant_pb = match cmd_opts_quiet {
-                true => ProgressBar::hidden(),
-                false => ProgressBar::new_spinner()
-                .with_style( // set style on progress bar
-                    ProgressStyle::default_spinner()
-    	            .template("Running mriqc on participant {msg} {spinner}")
-                        .tick_strings(&["", ".", "..", "...", ""])
-                )
-            };
-            let participant_pb = multibar.clone().add(participant_pb);
-            if !cmd_opts_quiet {
-                participant_pb.set_message(&participant);
-                participant_pb.enable_steady_tick(2000); // spin every 2 seconds
-            }
+        .map(|prepared| {
+            // Propagate any panic in the preparation task.
+            let prepared = prepared.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
             // Clone references we need to move into async block.
-            //let main_pb = main_pb.clone();
+            let multibar = multibar.clone();
             let interrupted = interrupted.clone();
-            let mriqc_options = mriqc_options.clone();
-            // Spawn mriqc for this participant and update progress bar.
+            let mut interrupt_rx = interrupt_rx.clone();
+            let admission = admission.clone();
+            let usage_log = usage_log.clone();
+            // Spawn mriqc for this batch and update progress bar.
             async move {
-                // Does this subject already exist in output directory?
-                let mut skip = false;
-                if cmd_opts_resume { // Only need to check if --resume on command line.
-                    if tokio::fs::metadata(mriqc_options.out_dir.join(format!("sub-{}", participant))).await.is_ok() {
-                        // Skip subject if --resume on command line and subject
-                        // already exists in output directory.
-                        skip = true;
-                    }
+                let (batch, workspace) = match prepared {
+                    Prepared::Ready(batch, workspace) => (batch, workspace),
+                    Prepared::Done(outcomes) => return outcomes
+                };
+                // Set up a progress bar for this batch.
+                let template = match batch.units().count() {
+                    1 => "Running mriqc on participant {msg} {spinner}",
+                    _ => "Running mriqc on participants {msg} {spinner}"
+                };
+                let participant_pb = match cmd_opts_quiet {
+                    true => ProgressBar::hidden(),
+                    false => ProgressBar::new_spinner()
+                    .with_style( // set style on progress bar
+                        ProgressStyle::default_spinner()
+                        .template(template)
+                            .tick_strings(&["", ".", "..", "...", ""])
+                    )
+                };
+                let participant_pb = multibar.add(participant_pb);
+                let batch_name = batch.to_string();
+                if !cmd_opts_quiet {
+                    participant_pb.set_message(&batch_name);
+                    participant_pb.enable_steady_tick(2000); // spin every 2 seconds
                 }
-                let res = match skip {
-                    // Skip running mriqc.
-                    true => Ok(()),
-                    // Await result of mriqc.
-                    false => async move {
-                        let options = Mriqc1Options {
-                            bids_dir: &mriqc_options.bids_dir,
-                            out_dir: &mriqc_options.out_dir,
-                            mriqc: Some(&mriqc_options.mriqc),
-                            work_dir: mriqc_options.work_dir.as_deref(),
-                            extra_args: mriqc_options.extra_args.iter().map(|s| s as &OsStr).collect(),
-                            participant: &participant
-                        };
-                        // Closure to interrupt the mriqc process.
-                        let cancel = cancel_on_interrupt_or_timeout(interrupted, cmd_opts_timeout, cmd_opts_quiet, participant.clone());
-                        // Spawn the mriqc process.
-                        let process = Mriqc1Process::new_with_cancel(options, cancel).await?;
-                        // Wait for it to either finish or be cancelled.
-                        process.wait().await?;
-                        // Make return type of Result<(), MriqcError> explicit.
-                        Ok::<(), MriqcError>(())
-                    }.await
+                // Wait until there is enough memory to start mriqc, unless we
+                // get interrupted first, in which case mriqc never runs.
+                let mut interrupt_wait = interrupt_rx.clone();
+                let _ticket = tokio::select! {
+                    ticket = admission.admit(|reason| participant_pb.set_message(&format!("{} (waiting, {})", batch_name, reason))) => ticket,
+                    _ = interrupt_wait.changed() => {
+                        participant_pb.finish_and_clear();
+                        return batch.interrupted();
+                    }
                 };
-                // Update progress bar before propagating errors.
-                // Finish this participant's progress bar.
+                participant_pb.set_message(&batch_name);
+                // Await result of mriqc.
+                let units: Vec<WorkUnit> = batch.units().cloned().collect();
+                let waiting_pb = participant_pb.clone();
+                let was_interrupted = interrupted.clone();
+                let mut summary = None;
+                let usage_summary = &mut summary;
+                let res = async move {
+                    // Cancel mriqc if it runs for too long.
+                    let mut workspace = *workspace;
+                    if let Some(timeout) = cmd_opts_timeout {
+                        workspace.set_timeout(timeout);
+                    }
+                    // Check for interrupts when Ctrl+C is pressed.
+                    workspace.set_wakeup(async move {
+                        let _ = interrupt_rx.changed().await;
+                    });
+                    // Closure to interrupt the mriqc process.
+                    let cancel = cancel_on_interrupt(interrupted);
+                    // Spawn the mriqc process.
+                    let process = workspace.spawn_with_cancel(cancel)?;
+                    // Wait for it to either finish or be cancelled, showing
+                    // the resources used by mriqc and its descendants.
+                    let mut monitor = match process.id() {
+                        Some(pid) => UsageMonitor::new(pid),
+                        None => return process.wait().await
+                    };
+                    let res = monitor.sample_while(process.wait(), std::time::Duration::from_secs(2), |monitor|
+                        waiting_pb.set_message(&format!("{} ({}; peak {})", batch_name, monitor.current(), monitor.peak()))
+                    ).await;
+                    *usage_summary = Some(monitor.summary());
+                    res
+                }.await;
+                // Finish this batch's progress bar.
                 participant_pb.finish_and_clear();
-                // Now we can propagate any errors.
-                res
+                // Work out the outcome for each participant in the batch.
+                let outcomes = match res {
+                    Ok(()) => batch.finished(),
+                    Err(error) => batch.failed(error).await
+                };
+                // Record each participant's peak usage.  The record is only
+                // informational, so ignore any errors writing it.  The usage
+                // was measured over the whole batch, so say which.
+                if let Some(summary) = summary {
+                    let batch = units.iter().map(|unit| unit.participant.dir_name()).collect::<Vec<_>>().join(",");
+                    for (unit, outcome) in units.iter().zip(&outcomes) {
+                        let status = match outcome {
+                            Ok(_) if was_interrupted.load(Ordering::Relaxed) => "cancelled",
+                            Ok(_) => "finished",
+                            Err(error) if error.is_timeout() => "timeout",
+                            Err(error) if error.is_out_of_memory() => "out_of_memory",
+                            Err(_) => "failed"
+                        };
+                        let session = unit.session.as_ref().map(|session| session.dir_name());
+                        let _ = usage_log.append(&unit.participant.dir_name(), session.as_deref(), &batch, status, &summary).await;
+                    }
+                }
+                outcomes
             }
         })
-        // Run N participants' mriqc processes in parallel.
+        // Run N instances of mriqc in parallel.
         .buffer_unordered(cmd_opts_n_par)
+        // Report the outcome of each participant in each batch.
+        .flat_map(futures_util::stream::iter)
         // Update the main progress bar.
         .progress_with(main_pb.clone())
         // Emit warnings and filter them out of the stream.
@@ -193,28 +457,41 @@ async fn main() -> Result<()> {
                 Ok(_) => None,
                 Err(warning) => Some(format!("Warning: {}\n", warning))
             };
+            // Timeouts are only ever warnings.
+            let timed_out = matches!(result, Err(error) if error.is_timeout());
+            // Let the user know about skipped participants.
+            let notice = match result {
+                Ok(Outcome::Skipped { unit, reason }) => Some(format!("Skipped participant {}: {}\n", unit, reason)),
+                _ => None
+            };
             // Should we warn on errors or propagate an error on error?
-            async move { match cmd_opts_werror {
-                // Don't convert warnings to errors.  Return true to pass them
-                // through as errors.  This will cause the stream to stop after
-                // encountering the first error.
-                true => true ,
-                false => match warning {
-                    // Filter out warnings by returning false.
-                    Some(warning) => match cmd_opts_quiet {
-                        true => false, // be quiet, no mesage on terminal
-                        false => { // emit warning message
-                            let mut stderr = tokio::io::stderr();
-                            // Ignore any issues writing message to stderr.
-                            let _ = stderr.write_all(warning.as_bytes()).await;
-                            false
-                        }
+            async move {
+                if let (Some(notice), false) = (notice, cmd_opts_quiet) {
+                    // Ignore any issues writing message to stderr.
+                    let _ = tokio::io::stderr().write_all(notice.as_bytes()).await;
+                }
+                match cmd_opts_werror && !timed_out {
+                    // Don't convert warnings to errors.  Return true to pass
+                    // them through as errors.  This will cause the stream to
+                    // stop after encountering the first error.
+                    true => true ,
+                    false => match warning {
+                        // Filter out warnings by returning false.
+                        Some(warning) => match cmd_opts_quiet {
+                            true => false, // be quiet, no mesage on terminal
+                            false => { // emit warning message
+                                let mut stderr = tokio::io::stderr();
+                                // Ignore any issues writing message to stderr.
+                                let _ = stderr.write_all(warning.as_bytes()).await;
+                                false
+                            }
+                        },
+                        // There was no warning/error, pass through Ok result
+                        // by returning true.
+                        None => true
                     },
-                    // There was no warning/error, pass through Ok result by
-                    // returning true.
-                    None => true
-                },
-            }}
+                }
+            }
         })
         // Await to poll stream to completion.  Cancel stream early on any
         // unfiltered errors that have propagated to this point.
@@ -242,30 +519,132 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-// Convenience function returns a closure that returns a cancel signal when
-// `interrupted` is true or after `timeout` (if any) has elapsed.
-fn cancel_on_interrupt_or_timeout(interrupted: Arc<AtomicBool>, timeout: Option<std::time::Duration>, quiet: bool, participant: String) -> impl FnMut()->Option<CancelSignal> {
-    let start_time = std::time::Instant::now();
-    move || {
-        // Have we been running for longer than the timeout?
-        let timed_out = match timeout {
-            // Maybe
-            Some(timeout) => {
-                let elapsed = std::time::Instant::now() - start_time;
-                let timed_out = elapsed > timeout;
-                if timed_out && !quiet {
-                    // Emit warning
-                    eprintln!("Participant {} timed out after {:?}.", participant, elapsed);
+// Check whether mriqc needs to run for a unit of work: whether it has any
+// scans for mriqc to process and, with --resume, whether their outputs are
+// already in the output directory.
+async fn check_unit(unit: WorkUnit, mriqc_options: Arc<MriqcOptions>, resume: bool, quiet: bool) -> Result<Checked, MriqcError> {
+    // Which scans does this subject have for mriqc to process?  If we don't
+    // recognize the modalities then let mriqc decide.
+    let outputs = match &mriqc_options.modalities {
+        Some(modalities) => {
+            let scans = matching_scans(&mriqc_options.bids_dir, &unit.participant, unit.session.as_ref(), modalities, mriqc_options.entity_filter.as_ref()).await?;
+            if scans.is_empty() {
+                return Ok(Checked::Done(Outcome::Skipped { unit, reason: "no matching scans".into() }));
+            }
+            Some(scans.into_iter().map(|scan| ExpectedOutputs::new(&mriqc_options.bids_dir, &mriqc_options.out_dir, scan)).collect::<Vec<_>>())
+        },
+        None => None
+    };
+    // Does this subject already have its outputs in the output directory?
+    if resume { // Only need to check if --resume on command line.
+        match &outputs {
+            Some(outputs) => {
+                let status = check_outputs(outputs).await;
+                if status.is_complete() {
+                    // Skip subject if --resume on command line and all its
+                    // outputs already exist.
+                    return Ok(Checked::Done(Outcome::Skipped { unit, reason: format!("already in output directory ({})", status) }));
+                }
+                // Let the user know why we're redoing a subject that was at
+                // least partly processed.
+                let started = status.complete > 0 || tokio::fs::metadata(mriqc_options.out_dir.join(unit.out_path())).await.is_ok();
+                if started && !quiet {
+                    let notice = format!("Rerunning participant {}: {}\n", unit, status);
+                    // Ignore any issues writing message to stderr.
+                    let _ = tokio::io::stderr().write_all(notice.as_bytes()).await;
                 }
-                timed_out
             },
-            // Timeout not set, so no
-            None => false
-        };
-        // Cancel if timed out or interrupted.
-        match timed_out || interrupted.load(Ordering::Relaxed) {
-            true => Some(CancelSignal::Interrupt),
-            false => None
+            // Without knowing which scans mriqc will process, fall back to
+            // checking for the subject's folder.
+            None => if tokio::fs::metadata(mriqc_options.out_dir.join(unit.out_path())).await.is_ok() {
+                return Ok(Checked::Done(Outcome::Skipped { unit, reason: "already in output directory".into() }));
+            }
         }
     }
+    Ok(Checked::Pending(PendingUnit { unit, outputs }))
+}
+
+// Remove temporary directories in `work_dir` left behind by instances of
+// mriqc1 that are no longer running.
+async fn clean_work_dir(work_dir: &Path, quiet: bool) -> Result<()> {
+    let scan = find_stale(work_dir).await?;
+    let mut removed = 0;
+    for dir in &scan.stale {
+        match dir.remove().await {
+            Ok(()) => {
+                removed += 1;
+                if !quiet {
+                    println!("Removed {} (pid {} on {} is gone).", dir.path.to_string_lossy(), dir.owner.pid, dir.owner.host);
+                }
+            },
+            Err(e) => if !quiet {
+                eprintln!("Warning: {:#}", anyhow::Error::from(e));
+            }
+        }
+    }
+    if !quiet {
+        for dir in &scan.in_use {
+            println!("Left {}, still in use by pid {}.", dir.path.to_string_lossy(), dir.owner.pid);
+        }
+        for dir in &scan.other_host {
+            println!("Left {}, created on host {}.", dir.path.to_string_lossy(), dir.owner.host);
+        }
+        println!("Removed {} stale temporary directories from {}.", removed, work_dir.to_string_lossy());
+    }
+    Ok(())
+}
+
+// Read newline-delimited participant labels from a file, or from standard
+// input if the path is `-`.  Blank lines and lines starting with `#` are
+// ignored.
+async fn read_participant_file(path: &Path) -> Result<Vec<ParticipantLabel>> {
+    match path.as_os_str() == "-" {
+        true => read_participants(tokio::io::stdin(), "standard input").await,
+        false => {
+            let source = format!("participant file {}", path.to_string_lossy());
+            let file = tokio::fs::File::open(path).await.context(format!("Couldn't read {}", source))?;
+            read_participants(file, &source).await
+        }
+    }
+}
+
+// Helper function reads participant labels, one per line, from `reader`,
+// skipping blank lines and `#` comments.  `source` describes the reader in
+// error messages.
+async fn read_participants<R: AsyncRead + Unpin>(mut reader: R, source: &str) -> Result<Vec<ParticipantLabel>> {
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents).await.context(format!("Couldn't read {}", source))?;
+    contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| ParticipantLabel::new(line).context(format!("Invalid participant in {}", source)))
+        .collect()
+}
+
+// Convenience function returns a closure that returns a cancel signal when
+// `interrupted` is true.
+fn cancel_on_interrupt(interrupted: Arc<AtomicBool>) -> impl FnMut()->Option<CancelSignal> {
+    move || match interrupted.load(Ordering::Relaxed) {
+        true => Some(CancelSignal::Interrupt),
+        false => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_participants() {
+        let labels = |labels: &[&str]| labels.iter().map(|label| label.parse().unwrap()).collect::<Vec<ParticipantLabel>>();
+        let contents = "# participants to rerun\nsub-01\n\n  02  \n# 03\nsub-04\n";
+        assert_eq!(read_participants(contents.as_bytes(), "standard input").await.unwrap(), labels(&["01", "02", "04"]));
+        assert!(read_participants("".as_bytes(), "standard input").await.unwrap().is_empty());
+        assert!(read_participants("01\nsub-0 2\n".as_bytes(), "standard input").await.is_err());
+
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), contents).unwrap();
+        assert_eq!(read_participant_file(file.path()).await.unwrap(), labels(&["01", "02", "04"]));
+        assert!(read_participant_file(&file.path().with_extension("missing")).await.is_err());
+    }
 }
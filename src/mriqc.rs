@@ -1,10 +1,16 @@
 //! This module contains tools for working with mriqc.
 
-use crate::bids::{BidsError, BidsParticipant, ShadowBids};
+use crate::bids::{find_scans, BidsError, BidsParticipant, BidsScan, EntityFilter, LinkStrategy, ParticipantLabel, SessionLabel, ShadowBids, ShadowBidsOptions};
 use crate::cancellable_process::{CancellableChild, CancelSignal};
+use crate::logs::{collect_logs, LogSources, LOGS_DIR};
+use crate::resources::{MemoryCap, MemoryLimit};
+use crate::work_dir::{write_owner, LockedDir, WorkDirError, OWNER_FILE};
 use std::ffi::{OsStr, OsString};
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tempfile::TempDir;
 use thiserror::Error;
 use tokio::process::Command;
@@ -43,9 +49,300 @@ pub enum MriqcError {
         /// Exit status/code of the process.
         status: Option<i32>
     },
+    /// mriqc failed while processing a batch of participants and did not
+    /// finish this participant.  The cause of the failure is reported in the
+    /// error for another participant in the same batch.
+    #[error("mriqc failed on a batch including participant {}, see the error for participant {}", participant, reported)]
+    BatchFailed {
+        /// The participant, e.g. `01` or `01 session 1`.
+        participant: String,
+        /// The participant whose error describes the cause of the failure.
+        reported: String,
+        /// Whether the batch failed because it timed out.
+        timed_out: bool
+    },
     /// There was an error setting up the shadow bids tree for this process.
     #[error(transparent)]
     BidsError(#[from] BidsError),
+    /// Couldn't tag the temporary directory with its owner.
+    #[error(transparent)]
+    WorkDirError(#[from] WorkDirError),
+    /// mriqc was cancelled because it ran past its timeout, see
+    /// [`Mriqc1Workspace::set_timeout()`].
+    #[error("mriqc timed out after {:?}{}", elapsed, stopped_by.map(|signal| format!(" and was stopped with {}", signal)).unwrap_or_default())]
+    Timeout {
+        /// How long mriqc ran for, including the time it took to stop.
+        elapsed: Duration,
+        /// The signal that finally stopped mriqc, see
+        /// [`crate::cancellable_process::Escalation`].
+        stopped_by: Option<CancelSignal>
+    },
+    /// mriqc failed and its temporary directory was kept for debugging, see
+    /// [`KeepWork`].
+    #[error("{}\nKept work directory: {}", error, path.to_string_lossy())]
+    KeptWork {
+        /// Why mriqc failed.
+        #[source]
+        error: Box<MriqcError>,
+        /// Where the temporary directory was kept.
+        path: PathBuf
+    },
+    /// mriqc failed and its crash files and logs were collected into a folder
+    /// in the output directory, see [`collect_logs()`].
+    #[error("{}\nCrash files and logs: {}", error, path.to_string_lossy())]
+    CollectedLogs {
+        /// Why mriqc failed.
+        #[source]
+        error: Box<MriqcError>,
+        /// Folder containing the collected files.
+        path: PathBuf
+    },
+    /// mriqc ran out of memory under its memory limit, see
+    /// [`Mriqc1Options::mem_limit`].
+    #[error("mriqc ran out of memory (limit {})\n{}", limit, error)]
+    OutOfMemory {
+        /// The memory limit.
+        limit: MemoryLimit,
+        /// How mriqc failed.
+        #[source]
+        error: Box<MriqcError>
+    },
+    /// Couldn't keep the temporary directory.
+    #[error("Couldn't keep work directory: {}", path.to_string_lossy())]
+    KeepWork {
+        /// The temporary directory.
+        path: PathBuf,
+        source: std::io::Error
+    },
+}
+impl MriqcError {
+    /// Check if this error is, or was caused by, mriqc timing out.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            MriqcError::Timeout { .. } => true,
+            MriqcError::KeptWork { error, .. } | MriqcError::CollectedLogs { error, .. } => error.is_timeout(),
+            MriqcError::BatchFailed { timed_out, .. } => *timed_out,
+            _ => false
+        }
+    }
+
+    /// Check if this error is, or was caused by, mriqc running out of memory.
+    pub fn is_out_of_memory(&self) -> bool {
+        match self {
+            MriqcError::OutOfMemory { .. } => true,
+            MriqcError::KeptWork { error, .. } | MriqcError::CollectedLogs { error, .. } => error.is_out_of_memory(),
+            _ => false
+        }
+    }
+
+    // Get mriqc's captured stdout and stderr, if any.
+    fn output(&self) -> Option<(&[u8], &[u8])> {
+        match self {
+            MriqcError::ProcessWithOutput { stdout, stderr, .. } => Some((stdout, stderr)),
+            MriqcError::OutOfMemory { error, .. } => error.output(),
+            _ => None
+        }
+    }
+
+    /// Get the path to the kept temporary directory, if any.
+    pub fn kept_work(&self) -> Option<&Path> {
+        match self {
+            MriqcError::KeptWork { path, .. } => Some(path),
+            _ => None
+        }
+    }
+}
+
+/// When to keep an instance of mriqc's temporary directory, which holds
+/// nipype's crash files and intermediate images, instead of removing it.
+/// Kept directories are renamed to `mriqc1-keep-sub-<label>` in the working
+/// directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeepWork {
+    /// Always remove the temporary directory.
+    #[default]
+    Never,
+    /// Keep the temporary directory if mriqc fails or times out.
+    OnFailure,
+    /// Always keep the temporary directory.
+    Always,
+}
+
+/// Imaging modality that mriqc can process, see mriqc's `-m/--modalities`
+/// argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Modality {
+    /// T1-weighted anatomical images, `anat/*_T1w.nii[.gz]`.
+    T1w,
+    /// T2-weighted anatomical images, `anat/*_T2w.nii[.gz]`.
+    T2w,
+    /// Blood-oxygen-level-dependent functional images,
+    /// `func/*_bold.nii[.gz]`.
+    Bold,
+    /// Diffusion-weighted images, `dwi/*_dwi.nii[.gz]`.
+    Dwi
+}
+impl Modality {
+    /// Modalities processed by mriqc when `-m/--modalities` is not given.
+    pub const DEFAULT: [Modality; 3] = [Modality::T1w, Modality::T2w, Modality::Bold];
+
+    /// Get the BIDS datatype directory containing images of this modality,
+    /// e.g. `anat`.
+    pub fn datatype(&self) -> &'static str {
+        match self {
+            Modality::T1w | Modality::T2w => "anat",
+            Modality::Bold => "func",
+            Modality::Dwi => "dwi"
+        }
+    }
+
+    /// Get the BIDS filename suffix for images of this modality, e.g. `T1w`.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Modality::T1w => "T1w",
+            Modality::T2w => "T2w",
+            Modality::Bold => "bold",
+            Modality::Dwi => "dwi"
+        }
+    }
+
+    /// Check if a participant's scan is of this modality.
+    pub fn matches_scan(&self, scan: &BidsScan) -> bool {
+        scan.datatype == self.datatype() && scan.name.suffix() == self.suffix()
+    }
+
+    /// Find the modalities requested by the `-m/--modalities` argument in a
+    /// list of arguments to pass through to mriqc.  Returns
+    /// [`Modality::DEFAULT`] if the argument is not present, or `None` if some
+    /// requested modality is not recognized.
+    pub fn from_args<S: AsRef<OsStr>>(args: &[S]) -> Option<Vec<Modality>> {
+        let mut modalities = Vec::new();
+        let mut found = false;
+        let mut args = args.iter().map(|arg| arg.as_ref().to_str()).peekable();
+        while let Some(arg) = args.next() {
+            // Values may be given as --modalities=T1w.
+            if let Some(value) = arg.and_then(|arg| arg.strip_prefix("--modalities=")) {
+                found = true;
+                modalities.push(value.parse().ok()?);
+                continue;
+            }
+            if arg != Some("-m") && arg != Some("--modalities") {
+                continue;
+            }
+            // Consume values up to, but not including, the next option.
+            found = true;
+            while let Some(value) = args.next_if(|value| !value.map(|value| value.starts_with('-')).unwrap_or(false)) {
+                modalities.push(value?.parse().ok()?);
+            }
+        }
+        match found {
+            true => Some(modalities),
+            false => Some(Modality::DEFAULT.to_vec())
+        }
+    }
+}
+impl std::str::FromStr for Modality {
+    type Err = String;
+    fn from_str(modality: &str) -> Result<Self, Self::Err> {
+        match modality {
+            "T1w" => Ok(Modality::T1w),
+            "T2w" => Ok(Modality::T2w),
+            "bold" => Ok(Modality::Bold),
+            "dwi" => Ok(Modality::Dwi),
+            _ => Err(format!("Unknown modality: {}", modality))
+        }
+    }
+}
+
+/// Find a participant's scans, or the scans in one of the participant's
+/// sessions, in the BIDS tree at `bids_dir` that mriqc would process for the
+/// given `modalities`.  If an entity filter is given then only scans matching
+/// the filter are returned.
+pub async fn matching_scans(bids_dir: &Path, participant: &ParticipantLabel, session: Option<&SessionLabel>, modalities: &[Modality], entity_filter: Option<&EntityFilter>) -> Result<Vec<BidsScan>, MriqcError> {
+    Ok(find_scans(bids_dir, participant, session).await?.into_iter().filter(|scan|
+        entity_filter.map(|filter| filter.matches_entities(scan.name.entities())).unwrap_or(true)
+            && modalities.iter().any(|modality| modality.matches_scan(scan))
+    ).collect())
+}
+
+/// Outputs that mriqc produces for one input scan.
+#[derive(Debug, Clone)]
+pub struct ExpectedOutputs {
+    /// The input scan.
+    pub scan: BidsScan,
+    /// Image quality metrics, e.g. `out/sub-01/anat/sub-01_T1w.json`.
+    pub iqms: PathBuf,
+    /// Visual report, e.g. `out/sub-01_T1w.html`.
+    pub report: PathBuf,
+}
+impl ExpectedOutputs {
+    /// Work out the outputs mriqc writes to `out_dir` for a `scan` in the BIDS
+    /// tree at `bids_dir`.  The IQMs mirror the scan's location in the BIDS
+    /// tree and the report is in the root of the output directory.
+    pub fn new(bids_dir: &Path, out_dir: &Path, scan: BidsScan) -> Self {
+        let stem = scan.name.stem();
+        let scan_dir = scan.path.parent()
+            .and_then(|parent| parent.strip_prefix(bids_dir).ok())
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from(&scan.datatype));
+        Self {
+            iqms: out_dir.join(scan_dir).join(format!("{}.json", stem)),
+            report: out_dir.join(format!("{}.html", stem)),
+            scan
+        }
+    }
+}
+
+/// Which of the [`ExpectedOutputs`] of a participant's scans are already
+/// present in the output directory.  See [`check_outputs()`].
+#[derive(Debug, Clone, Default)]
+pub struct OutputStatus {
+    /// Number of scans checked.
+    pub scans: usize,
+    /// Number of scans that have all their outputs.
+    pub complete: usize,
+    /// Paths to outputs that are missing.
+    pub missing: Vec<PathBuf>,
+}
+impl OutputStatus {
+    /// Check if there were scans to check and all of them have their
+    /// outputs.
+    pub fn is_complete(&self) -> bool {
+        self.scans > 0 && self.complete == self.scans
+    }
+}
+impl std::fmt::Display for OutputStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {} scans have IQMs and reports", self.complete, self.scans)?;
+        if let Some(first) = self.missing.first() {
+            write!(f, ", missing {}", first.to_string_lossy())?;
+            if self.missing.len() > 1 {
+                write!(f, " and {} more", self.missing.len() - 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check which expected outputs already exist.
+pub async fn check_outputs(outputs: &[ExpectedOutputs]) -> OutputStatus {
+    let mut status = OutputStatus {
+        scans: outputs.len(),
+        ..Default::default()
+    };
+    for output in outputs {
+        let mut complete = true;
+        for path in &[&output.iqms, &output.report] {
+            if tokio::fs::metadata(path).await.is_err() {
+                status.missing.push(path.to_path_buf());
+                complete = false;
+            }
+        }
+        if complete {
+            status.complete += 1;
+        }
+    }
+    status
 }
 
 /// Options for [`Mriqc1Process::new()`]
@@ -54,8 +351,13 @@ pub struct Mriqc1Options<'a> {
     pub bids_dir: &'a Path,
     /// Output directory.
     pub out_dir: &'a Path,
-    /// Participant id.
-    pub participant: &'a str,
+    /// Labels of the participants to process.  Usually this is one
+    /// participant, but a batch of several participants can share one
+    /// instance of mriqc.  Must not be empty.
+    pub participants: Vec<&'a ParticipantLabel>,
+    /// Session id.  If given, only this session of each participant is
+    /// processed.  Otherwise all of the participants' sessions are processed.
+    pub session: Option<&'a SessionLabel>,
     /// Path to mriqc binary.  Defaults to `mriqc`.
     pub mriqc: Option<&'a Path>,
     /// Where to create temporary files.  Defaults to system temporary
@@ -63,50 +365,151 @@ pub struct Mriqc1Options<'a> {
     pub work_dir: Option<&'a Path>,
     /// Vector of additional arguments to pass through to mriqc.
     pub extra_args: Vec<&'a OsStr>,
+    /// Options for the shadow BIDS tree.  Defaults to
+    /// [`ShadowBidsOptions::default()`].
+    pub shadow_options: Option<&'a ShadowBidsOptions>,
+    /// Only link the participants' files that match this filter into the
+    /// shadow BIDS tree, see [`BidsParticipant::new_filtered()`].  Defaults to
+    /// linking each participant's whole directory.
+    pub entity_filter: Option<&'a EntityFilter>,
+    /// Copy the participants' data into the temporary directory instead of
+    /// linking it, e.g. to stage data from a slow network filesystem onto
+    /// local scratch.  The copy is removed along with the temporary directory.
+    pub stage: bool,
+    /// When to keep the temporary directory.  Defaults to
+    /// [`KeepWork::Never`].
+    pub keep_work: KeepWork,
+    /// Instead of a new temporary directory with a random name, reuse the
+    /// directory `mriqc1-sub-<label>` in the working directory across
+    /// attempts, so that nipype's cache can skip the steps that finished in a
+    /// previous attempt.  The directory is locked while in use and is kept if
+    /// mriqc fails or is cancelled.  It is removed when mriqc succeeds unless
+    /// `keep_work` is [`KeepWork::Always`].  A batch's directory is named
+    /// after its first participant, so only reuse directories for batches
+    /// that are made up the same way on every attempt, e.g. of one
+    /// participant.
+    pub reuse_work: bool,
+    /// Limit the memory used by mriqc, see [`MemoryCap`].  If mriqc runs out
+    /// of memory then [`Mriqc1Process::wait()`] returns
+    /// [`MriqcError::OutOfMemory`].  Defaults to no limit.
+    pub mem_limit: Option<MemoryLimit>,
 }
 
-/// Resources for an instance of mriqc processing a single participant.
-pub struct Mriqc1Process<F> {
-    // mriqc process
-    process: CancellableChild<F>,
-    // BIDS filesystem resources for the participant being processed.
-    _bids_participant: BidsParticipant,
+// Directory in which an instance of mriqc does its work.
+enum WorkspaceDir {
+    // Temporary directory with a random name, shared with the shadow BIDS
+    // tree.
+    Temp(Arc<TempDir>),
+    // Directory reused across attempts.
+    Reused(LockedDir)
+}
+impl WorkspaceDir {
+    fn path(&self) -> &Path {
+        match self {
+            WorkspaceDir::Temp(temp_dir) => temp_dir.path(),
+            WorkspaceDir::Reused(dir) => dir.path()
+        }
+    }
+}
+
+/// Temporary directory and shadow BIDS tree prepared for an instance of mriqc
+/// to process one participant, or a batch of participants.  Preparing the
+/// workspace ahead of time lets slow work such as staging the participants'
+/// data (see
+/// [`Mriqc1Options::stage`]) overlap with other instances of mriqc.
+pub struct Mriqc1Workspace {
+    // BIDS filesystem resources for the participants being processed.  Each
+    // holds the shadow BIDS tree and temporary directory.
+    bids_participants: Vec<BidsParticipant>,
+    // Temporary directory for this instance of mriqc.  Declared after
+    // `bids_participants` so that the shadow BIDS tree is removed first.
+    temp_dir: WorkspaceDir,
     // The command, e.g. `/usr/local/bin/mriqc`.
     cmd: OsString,
     // Command line arguments.
-    args: Vec<OsString>
+    args: Vec<OsString>,
+    // Output directory.
+    out_dir: PathBuf,
+    // When to keep the temporary directory.
+    keep_work: KeepWork,
+    // Name for the temporary directory if it is kept, e.g. `sub-01_ses-1`.
+    keep_name: String,
+    // Cancel mriqc if it runs for longer than this.
+    timeout: Option<Duration>,
+    // Limit mriqc's memory.
+    mem_limit: Option<MemoryLimit>,
+    // Resolves when the cancel closure should be checked.
+    wakeup: Option<Pin<Box<dyn Future<Output = ()> + Send>>>
 }
-impl<F: FnMut() -> Option<CancelSignal> + Unpin> Mriqc1Process<F> {
-    /// Invoke an instance of mriqc to process one participant with the provided
-    /// `options`.  The closure `cancel` is called periodically, and if returns
-    /// some [`CancelSignal`] then then this instance of mriqc will be cancelled
-    /// (i.e. interrupted, aborted); return `None` from the closure to continue
-    /// processing.
-    pub async fn new_with_cancel(options: Mriqc1Options<'_>, cancel: F) -> Result<Self, MriqcError> {
+impl Mriqc1Workspace {
+    /// Prepare the temporary directory and shadow BIDS tree for an instance of
+    /// mriqc to process the participants given in `options`.  Use
+    /// [`Mriqc1Workspace::spawn_with_cancel()`] to start mriqc.  The
+    /// workspace is removed when it is dropped.
+    pub async fn new(options: Mriqc1Options<'_>) -> Result<Self, MriqcError> {
         // Destructure options and set default values.
         let bids_dir = options.bids_dir;
         let out_dir = options.out_dir;
-        let participant = options.participant;
+        let participants = options.participants;
+        debug_assert!(!participants.is_empty());
+        let session = options.session;
         let mriqc = options.mriqc.unwrap_or(Path::new("mriqc"));
         let work_dir = match options.work_dir {
             Some(work_dir) => work_dir.into(),
             None => std::env::temp_dir()
         };
         let extra_args = options.extra_args;
+        let mut shadow_options = options.shadow_options.cloned().unwrap_or_default();
+        if options.stage {
+            shadow_options.participant_link_strategy = Some(LinkStrategy::Copy);
+        }
+
+        // Name the temporary directory after the participants in case we keep
+        // or reuse it.
+        let mut keep_name = participants[0].dir_name();
+        if participants.len() > 1 {
+            keep_name.push_str(&format!("_and_{}_more", participants.len() - 1));
+        }
+        if let Some(session) = session {
+            keep_name.push('_');
+            keep_name.push_str(&session.dir_name());
+        }
 
         // Set up the shadow BIDS tree.
-        // Create a unique temporary directory within the working directory with
-        // a randomly assigned name.
-        let temp_dir = Arc::new(TempDir::new_in(&work_dir).map_err(|source|
-            MriqcError::TempDir{work_dir, source}
-        )?);
-        // Create the shadow BIDS tree in the temporary directory.
-        let shadow_bids = Arc::new(ShadowBids::new_with_parent(bids_dir, temp_dir.clone()).await?);
+        let (temp_dir, shadow_bids) = match options.reuse_work {
+            // Lock the reused directory and create the shadow BIDS tree in
+            // it, at the same path as in previous attempts.
+            true => {
+                let dir = LockedDir::new(work_dir.join(format!("mriqc1-{}", keep_name))).await?;
+                let shadow_bids = ShadowBids::new_in(bids_dir, dir.path(), &shadow_options).await?;
+                (WorkspaceDir::Reused(dir), shadow_bids)
+            },
+            false => {
+                // Create a unique temporary directory within the working
+                // directory with a randomly assigned name.
+                let temp_dir = Arc::new(TempDir::new_in(&work_dir).map_err(|source|
+                    MriqcError::TempDir{work_dir, source}
+                )?);
+                // Record who owns the temporary directory so that it can be
+                // cleaned up if we are killed before it is dropped.
+                write_owner(temp_dir.path()).await?;
+                // Create the shadow BIDS tree in the temporary directory.
+                let shadow_bids = ShadowBids::new_with_parent(bids_dir, temp_dir.clone(), &shadow_options).await?;
+                (WorkspaceDir::Temp(temp_dir), shadow_bids)
+            }
+        };
+        let shadow_bids = Arc::new(shadow_bids);
         let shadow_bids_path = shadow_bids.path();
-        // Register the BIDS participant within the shadow BIDS tree.
-        let bids_participant = BidsParticipant::new(participant, shadow_bids.clone()).await?;
+        // Register the BIDS participants within the shadow BIDS tree.
+        let mut bids_participants = Vec::with_capacity(participants.len());
+        for participant in &participants {
+            bids_participants.push(match (options.entity_filter, session) {
+                (Some(filter), session) => BidsParticipant::new_filtered(participant, session, filter, shadow_bids.clone()).await?,
+                (None, Some(session)) => BidsParticipant::new_session(participant, session, shadow_bids.clone()).await?,
+                (None, None) => BidsParticipant::new(participant, shadow_bids.clone()).await?
+            });
+        }
 
-        // Spawn the mriqc process.
         // Compose command line arguments.
         let args = {
             // Mandary command line arguments.
@@ -115,63 +518,254 @@ impl<F: FnMut() -> Option<CancelSignal> + Unpin> Mriqc1Process<F> {
                 out_dir.as_os_str().into(), // output directory
                 OsStr::new("participant").into(), // do participant-level analysis
                 OsStr::new("--work-dir").into(), temp_dir.path().as_os_str().into(), // use temporary directory as working directory for this instance of mriqc
-                OsStr::new("--participant-label").into(), OsStr::new(participant).into() // specify one participant label, correponding to this one participant we want to process
+                OsStr::new("--participant-label").into() // specify the participant labels we want to process
             ];
+            args.extend(participants.iter().map(|participant| OsStr::new(participant.as_str()).into()));
+            // Restrict processing to one session.
+            if let Some(session) = session {
+                args.push(OsStr::new("--session-id").into());
+                args.push(OsStr::new(session.as_str()).into());
+            }
             // Append extra arguments.
             args.extend(extra_args.into_iter().map(|arg| arg.into()));
             args
         };
-        // Build the command and spawn the process.
-        let process = Command::new(mriqc)
-            .args(&args)
+
+        Ok(Self {
+            bids_participants,
+            temp_dir,
+            cmd: mriqc.into(),
+            args,
+            out_dir: out_dir.into(),
+            keep_work: options.keep_work,
+            keep_name,
+            timeout: None,
+            mem_limit: options.mem_limit,
+            wakeup: None
+        })
+    }
+
+    /// Cancel mriqc if it is still running `timeout` after it is spawned, in
+    /// which case [`Mriqc1Process::wait()`] returns [`MriqcError::Timeout`].
+    /// See [`CancellableChild::with_deadline()`].
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
+    /// Check the cancel closure passed to
+    /// [`Mriqc1Workspace::spawn_with_cancel()`] when `wakeup` resolves.  mriqc
+    /// runs in its own process group, so it doesn't see Ctrl+C from the
+    /// terminal, and the closure would otherwise only be checked when mriqc
+    /// writes output or exits.  See [`CancellableChild::with_wakeup()`].
+    pub fn set_wakeup<W: Future<Output = ()> + Send + 'static>(&mut self, wakeup: W) {
+        self.wakeup = Some(Box::pin(wakeup));
+    }
+
+    // Keep the temporary directory by renaming it to
+    // `<work_dir>/mriqc1-keep-<keep_name>`, or with a numeric suffix if that
+    // already exists.  A reused directory stays where it is.  The shadow BIDS
+    // tree is removed first.
+    async fn keep(self) -> Result<PathBuf, MriqcError> {
+        let Mriqc1Workspace { bids_participants, temp_dir, keep_name, .. } = self;
+        drop(bids_participants);
+        let temp_dir = match temp_dir {
+            WorkspaceDir::Temp(temp_dir) => temp_dir,
+            WorkspaceDir::Reused(dir) => return Ok(dir.path().into())
+        };
+        let temp_dir = match Arc::try_unwrap(temp_dir) {
+            Ok(temp_dir) => temp_dir.keep(),
+            // Somebody else is holding on to the temporary directory, so we
+            // can't stop it from being removed.
+            Err(temp_dir) => return Err(MriqcError::KeepWork {
+                path: temp_dir.path().into(),
+                source: std::io::Error::other("temporary directory is still in use")
+            })
+        };
+        let work_dir = temp_dir.parent().map(Path::to_path_buf).unwrap_or_default();
+        let mut kept = work_dir.join(format!("mriqc1-keep-{}", keep_name));
+        let mut n = 1;
+        while tokio::fs::symlink_metadata(&kept).await.is_ok() {
+            n += 1;
+            kept = work_dir.join(format!("mriqc1-keep-{}-{}", keep_name, n));
+        }
+        tokio::fs::rename(&temp_dir, &kept).await.map_err(|source| MriqcError::KeepWork {
+            path: temp_dir,
+            source
+        })?;
+        // The directory no longer belongs to a running process, but it
+        // shouldn't be removed by `mriqc1 --clean` either.
+        let _ = tokio::fs::remove_file(kept.join(OWNER_FILE)).await;
+        Ok(kept)
+    }
+
+    // Collect mriqc's crash files and logs after it failed with `error` into
+    // `<out_dir>/logs/mriqc1/<keep_name>`.  Returns `error` wrapped in
+    // [`MriqcError::CollectedLogs`], or unchanged if there was nothing to
+    // collect or collecting failed.
+    async fn collect_logs(&self, error: MriqcError, since: SystemTime) -> MriqcError {
+        let (stdout, stderr) = match error.output() {
+            Some((stdout, stderr)) => (Some(stdout), Some(stderr)),
+            None => (None, None)
+        };
+        let sources = LogSources {
+            work_dir: self.path(),
+            out_dir: &self.out_dir,
+            since,
+            stdout,
+            stderr
+        };
+        let dest = self.out_dir.join("logs").join(LOGS_DIR).join(&self.keep_name);
+        match collect_logs(&sources, &dest).await {
+            Ok(collected) if !collected.is_empty() => MriqcError::CollectedLogs {
+                error: Box::new(error),
+                path: dest
+            },
+            _ => error
+        }
+    }
+
+    // Remove the shadow BIDS tree and then the temporary directory, including
+    // a reused directory.
+    async fn remove(self) -> Result<(), MriqcError> {
+        let Mriqc1Workspace { bids_participants, temp_dir, .. } = self;
+        drop(bids_participants);
+        match temp_dir {
+            WorkspaceDir::Temp(_) => Ok(()),
+            WorkspaceDir::Reused(dir) => Ok(dir.remove().await?)
+        }
+    }
+
+    /// Get the path to the temporary directory, which is also mriqc's working
+    /// directory.
+    pub fn path(&self) -> &Path {
+        self.temp_dir.path()
+    }
+
+    /// Get the participants' data within the shadow BIDS tree.
+    pub fn participants(&self) -> &[BidsParticipant] {
+        &self.bids_participants
+    }
+
+    /// Spawn mriqc in this workspace.  See
+    /// [`Mriqc1Process::new_with_cancel()`] for the meaning of `cancel`.  The
+    /// workspace is removed when the returned process is dropped.
+    pub fn spawn_with_cancel<F: FnMut() -> Option<CancelSignal> + Unpin>(mut self, cancel: F) -> Result<Mriqc1Process<F>, MriqcError> {
+        // Build the command.
+        let mut command = Command::new(&self.cmd);
+        command.args(&self.args)
             .stdin(std::process::Stdio::null()) // no keyboard input to process
             .stdout(std::process::Stdio::piped()) // capture stdout
             .stderr(std::process::Stdio::piped()) // capture stderr
-            .current_dir(temp_dir.path()) // make working directory this instance's temporary directory
-            .kill_on_drop(true) // if this object is dropped mriqc's resources will be destroyed, so we should kill the process
-            .spawn() // fire it up!
+            .current_dir(self.temp_dir.path()) // make working directory this instance's temporary directory
+            .kill_on_drop(true); // if this object is dropped mriqc's resources will be destroyed, so we should kill the process
+        // Limit mriqc's memory, including its workers'.
+        let memory_cap = self.mem_limit.map(|limit| MemoryCap::new(limit, &self.keep_name));
+        if let Some(memory_cap) = &memory_cap {
+            memory_cap.apply(&mut command);
+        }
+        // Spawn the process in its own process group so that cancelling it
+        // also stops nipype's workers and the tools they run, and wrap it
+        // inside a CancellableChild.
+        let started = Instant::now();
+        let mut process = CancellableChild::spawn_group(&mut command, cancel)
             .map_err(|source| // wrap error in context
                 MriqcError::Process {
-                    cmd: mriqc.into(),
-                    args: args.clone(),
+                    cmd: self.cmd.clone(),
+                    args: self.args.clone(),
                     source // cause of this error
                 }
             )?;
-        // Wrap inside a CancellableChild.
-        let process = CancellableChild::new(process, cancel);
+        if let Some(timeout) = self.timeout {
+            process = process.with_deadline(started + timeout);
+        }
+        if let Some(wakeup) = self.wakeup.take() {
+            process = process.with_wakeup(wakeup);
+        }
 
-        // Construct self.
+        // Construct the process, which takes ownership of the workspace.
         Ok(Mriqc1Process {
             process,
-            _bids_participant: bids_participant,
-            cmd: mriqc.into(),
-            args
+            workspace: self,
+            memory_cap,
+            started,
+            started_at: SystemTime::now()
         })
     }
+}
+
+/// Resources for an instance of mriqc processing one participant, or a batch
+/// of participants.
+pub struct Mriqc1Process<F> {
+    // mriqc process
+    process: CancellableChild<F>,
+    // Temporary directory and BIDS filesystem resources for the participants
+    // being processed.  Dropped after the process is killed.
+    workspace: Mriqc1Workspace,
+    // mriqc's memory limit, if any.
+    memory_cap: Option<MemoryCap>,
+    // When the process was spawned.
+    started: Instant,
+    // When the process was spawned, by the wall clock.
+    started_at: SystemTime
+}
+impl<F: FnMut() -> Option<CancelSignal> + Unpin> Mriqc1Process<F> {
+    /// Invoke an instance of mriqc to process the participants given in
+    /// `options`.  The closure `cancel` is called periodically, and if returns
+    /// some [`CancelSignal`] then then this instance of mriqc will be cancelled
+    /// (i.e. interrupted, aborted); return `None` from the closure to continue
+    /// processing.
+    pub async fn new_with_cancel(options: Mriqc1Options<'_>, cancel: F) -> Result<Self, MriqcError> {
+        Mriqc1Workspace::new(options).await?.spawn_with_cancel(cancel)
+    }
+
+    /// Get the process id of mriqc, which leads its own process group, e.g.
+    /// to monitor its resource usage with [`crate::usage::UsageMonitor`].
+    /// Returns `None` if mriqc has already been reaped.
+    pub fn id(&self) -> Option<u32> {
+        self.process.id()
+    }
+
     /// Wait for this mriqc process to finish, or for the process to be
     /// cancelled via its cancel closure (see
     /// [`Mriqc1Process::new_with_cancel`]), whichever comes first.  If the
-    /// process finished successfully or if it was cancelled returns `Ok(())`.
-    /// Otherwise returns an error.
+    /// process finished successfully or if it was cancelled returns `Ok(())`,
+    /// unless it was cancelled after its timeout (see
+    /// [`Mriqc1Workspace::set_timeout()`]) in which case it returns
+    /// [`MriqcError::Timeout`].  Otherwise returns an error, which is
+    /// [`MriqcError::OutOfMemory`] if mriqc ran out of memory (see
+    /// [`Mriqc1Options::mem_limit`]).  On error, nipype's crash files and
+    /// mriqc's logs are collected into `<out_dir>/logs/mriqc1/sub-<label>`
+    /// before the temporary directory is removed and the error is wrapped in
+    /// [`MriqcError::CollectedLogs`].  If the temporary directory is kept (see
+    /// [`KeepWork`]) then errors are wrapped in [`MriqcError::KeptWork`].
     pub async fn wait(self) -> Result<(), MriqcError> {
-        match self.process.wait_with_output().await {
+        let Mriqc1Process { process, workspace, memory_cap, started, started_at } = self;
+        let output = process.wait_with_output().await;
+        // Was mriqc cancelled, e.g. by Ctrl+C or by its timeout?
+        let cancelled = matches!(&output, Ok(output) if output.how_cancelled.is_some());
+        let res = match output {
             // We successfully waited.
-            Ok(output) => match output.how_cancelled {
-                // The child was cancelled.  Return sucecss.
-                Some(_) => Ok(()),
+            Ok(output) => match (output.how_cancelled, output.stopped_by) {
+                // The child was cancelled.  Did it run past its deadline?
+                (Some(_), stopped_by) => match output.timed_out {
+                    true => Err(MriqcError::Timeout {
+                        elapsed: started.elapsed(),
+                        stopped_by
+                    }),
+                    // Otherwise return success.
+                    false => Ok(())
+                },
                 // The child wasn't cancelled.  Inspect the output.
-                None => {
-                    // If child was not cancelled then unwrap() is guaranteed
-                    // not to panic.
-                    let output = output.output.unwrap();
+                (None, _) => {
+                    let output = output.output;
                     match output.status.success() {
                         // The child finished succesfully.  Return success.
                         true => Ok(()),
                         // There was an error, but we have some output to help`
                         // figure out what happened.
                         false => Err(MriqcError::ProcessWithOutput {
-                            cmd: self.cmd,
-                            args: self.args,
+                            cmd: workspace.cmd.clone(),
+                            args: workspace.args.clone(),
                             stdout: output.stdout,
                             stderr: output.stderr,
                             status: output.status.code()
@@ -181,10 +775,51 @@ impl<F: FnMut() -> Option<CancelSignal> + Unpin> Mriqc1Process<F> {
             },
             // An error happened and we didn't get any output.
             Err(source) => Err(MriqcError::Process {
-                cmd: self.cmd,
-                args: self.args,
+                cmd: workspace.cmd.clone(),
+                args: workspace.args.clone(),
                 source
             })
+        };
+        // Did mriqc fail because it ran out of memory?  Timeouts are reported
+        // as such.
+        let res = match (res, memory_cap) {
+            (Err(error), Some(memory_cap)) if !error.is_timeout() && memory_cap.out_of_memory(error.output().map(|(_, stderr)| stderr).unwrap_or_default()) => Err(MriqcError::OutOfMemory {
+                limit: memory_cap.limit(),
+                error: Box::new(error)
+            }),
+            (res, _) => res
+        };
+        // Save the crash files and logs before the temporary directory is
+        // removed.
+        let res = match res {
+            Err(error) => Err(workspace.collect_logs(error, started_at).await),
+            ok => ok
+        };
+        // Should we keep the temporary directory?  A reused directory is
+        // only removed after mriqc succeeds, so that the next attempt can use
+        // its cache if mriqc failed or was cancelled.
+        let reused = matches!(workspace.temp_dir, WorkspaceDir::Reused(_));
+        let keep = match (workspace.keep_work, reused) {
+            (KeepWork::Always, _) => true,
+            (_, true) => res.is_err() || cancelled,
+            (KeepWork::OnFailure, false) => res.is_err(),
+            (KeepWork::Never, false) => false
+        };
+        if !keep {
+            return match (res, workspace.remove().await) {
+                (Ok(()), removed) => removed,
+                (Err(error), _) => Err(error)
+            };
+        }
+        match (res, workspace.keep().await) {
+            (Ok(()), kept) => kept.map(|_| ()),
+            (Err(error), Ok(path)) => Err(MriqcError::KeptWork {
+                error: Box::new(error),
+                path
+            }),
+            // Report why mriqc failed rather than why we couldn't keep its
+            // temporary directory.
+            (Err(error), Err(_)) => Err(error)
         }
     }
 }
@@ -200,3 +835,34 @@ impl Mriqc1Process<fn() -> Option<CancelSignal>> {
 fn never_cancel() -> Option<CancelSignal> {
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bids::BidsPath;
+
+    #[test]
+    fn test_modalities_from_args() {
+        let none: [&str; 0] = [];
+        assert_eq!(Modality::from_args(&none), Some(Modality::DEFAULT.to_vec()));
+        assert_eq!(Modality::from_args(&["-m", "T1w", "bold", "--no-sub"]), Some(vec![Modality::T1w, Modality::Bold]));
+        assert_eq!(Modality::from_args(&["--no-sub", "--modalities", "dwi"]), Some(vec![Modality::Dwi]));
+        assert_eq!(Modality::from_args(&["--modalities=T2w"]), Some(vec![Modality::T2w]));
+        assert_eq!(Modality::from_args(&["-m", "T1w", "-m", "bold"]), Some(vec![Modality::T1w, Modality::Bold]));
+        assert_eq!(Modality::from_args(&["--modalities", "T1w", "--modalities=dwi", "-m", "T2w"]), Some(vec![Modality::T1w, Modality::Dwi, Modality::T2w]));
+        assert_eq!(Modality::from_args(&["-m", "flair"]), None);
+    }
+
+    #[test]
+    fn test_expected_outputs() {
+        let path = PathBuf::from("/bids/sub-01/ses-1/func/sub-01_ses-1_task-rest_bold.nii.gz");
+        let scan = BidsScan {
+            name: BidsPath::from_path(&path).unwrap(),
+            path,
+            datatype: "func".into()
+        };
+        let outputs = ExpectedOutputs::new(Path::new("/bids"), Path::new("/out"), scan);
+        assert_eq!(outputs.iqms, Path::new("/out/sub-01/ses-1/func/sub-01_ses-1_task-rest_bold.json"));
+        assert_eq!(outputs.report, Path::new("/out/sub-01_ses-1_task-rest_bold.html"));
+    }
+}
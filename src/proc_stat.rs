@@ -0,0 +1,64 @@
+//! Read a process's status from `/proc/<pid>/stat`, see `proc(5)`.
+
+/// The fields of `/proc/<pid>/stat` that we care about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stat {
+    /// Process id.
+    pub pid: u32,
+    /// Process id of the parent.
+    pub ppid: u32,
+    /// Process group id.
+    pub pgrp: u32,
+    /// User plus system CPU time in clock ticks.
+    pub cpu_ticks: u64,
+    /// Start time in clock ticks since boot.
+    pub start_ticks: u64,
+    /// Resident memory in pages.
+    pub rss_pages: u64,
+}
+impl Stat {
+    /// Read the status of process `pid`.  `/proc` is in memory, so this
+    /// doesn't block for long.
+    pub fn read(pid: u32) -> std::io::Result<Self> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+        Self::parse(&stat).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "couldn't parse /proc/<pid>/stat"))
+    }
+
+    // Parse the contents of /proc/<pid>/stat.  The second field is the
+    // command name in parentheses, which may itself contain spaces and
+    // parentheses, so the remaining fields start after the last `)`.
+    fn parse(stat: &str) -> Option<Self> {
+        let (pid, rest) = stat.split_once(" (")?;
+        let (_, rest) = rest.rsplit_once(')')?;
+        // rest starts with field 3, the state.
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+        Some(Self {
+            pid: pid.trim().parse().ok()?,
+            ppid: field(4)? as u32,
+            pgrp: field(5)? as u32,
+            cpu_ticks: field(14)? + field(15)?,
+            start_ticks: field(22)?,
+            rss_pages: field(24)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "1234 (odd) name) S 1 1234 1234 0 -1 4194560 100 0 0 0 150 50 0 0 20 0 1 0 98765 1000 10";
+        assert_eq!(Stat::parse(stat), Some(Stat {
+            pid: 1234,
+            ppid: 1,
+            pgrp: 1234,
+            cpu_ticks: 200,
+            start_ticks: 98765,
+            rss_pages: 10
+        }));
+        assert_eq!(Stat::read(std::process::id()).unwrap().pid, std::process::id());
+    }
+}
@@ -0,0 +1,337 @@
+//! Limit the memory used by each instance of mriqc.
+//!
+//! Each instance of mriqc gets its own cgroup v2 under the cgroup that
+//! mriqc1 runs in, with `memory.max` set to the limit, so that the kernel
+//! stops the instance rather than letting it exhaust the system's RAM.  This
+//! requires the memory controller to be delegated to us, e.g. by running
+//! mriqc1 with `systemd-run --user --scope -p Delegate=yes`.  When cgroups
+//! aren't available each process of the instance is limited with
+//! `RLIMIT_AS` instead, which caps virtual rather than resident memory and
+//! applies to every process separately.
+
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tokio::process::Command;
+
+/// Amount of memory in bytes, parsed from strings such as `16G` or `512M`.
+/// Suffixes `K`, `M`, `G` and `T` are powers of 1024, may be lower case, and
+/// may be followed by `B` or `iB`.  A number without a suffix is in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MemoryLimit(u64);
+impl MemoryLimit {
+    // Units in decreasing order of size.
+    const UNITS: [(char, u64); 4] = [('T', 1 << 40), ('G', 1 << 30), ('M', 1 << 20), ('K', 1 << 10)];
+
+    /// Create a limit of `bytes` bytes.
+    pub fn from_bytes(bytes: u64) -> Self {
+        Self(bytes)
+    }
+
+    /// Get the limit in bytes.
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+impl std::str::FromStr for MemoryLimit {
+    type Err = String;
+    fn from_str(limit: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Invalid memory size: {}, expected e.g. 16G", limit);
+        let upper = limit.trim().to_ascii_uppercase();
+        let trimmed = upper.strip_suffix("IB")
+            .or_else(|| upper.strip_suffix('B'))
+            .unwrap_or(&upper);
+        let (number, scale) = match trimmed.chars().last() {
+            Some(unit) if unit.is_ascii_alphabetic() => match Self::UNITS.iter().find(|(name, _)| *name == unit) {
+                Some((_, scale)) => (&trimmed[..trimmed.len() - 1], *scale),
+                None => return Err(error())
+            },
+            _ => (trimmed, 1)
+        };
+        // Allow fractions such as 1.5G.
+        let number: f64 = number.parse().map_err(|_| error())?;
+        let bytes = number * scale as f64;
+        match bytes.is_finite() && bytes >= 1.0 && bytes < u64::MAX as f64 {
+            true => Ok(Self(bytes as u64)),
+            false => Err(error())
+        }
+    }
+}
+impl std::fmt::Display for MemoryLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Use the largest unit that divides the limit evenly.
+        match Self::UNITS.iter().find(|(_, scale)| self.0.checked_rem(*scale) == Some(0)) {
+            Some((unit, scale)) => write!(f, "{}{}", self.0 / scale, unit),
+            None => write!(f, "{}", self.0)
+        }
+    }
+}
+
+/// Memory limit for one instance of mriqc, see the [module
+/// documentation](self).  Apply it to the command that starts mriqc with
+/// [`MemoryCap::apply()`] and check whether mriqc ran out of memory with
+/// [`MemoryCap::out_of_memory()`].  The instance's cgroup, if any, is removed
+/// when the cap is dropped, killing any processes left in it.
+#[derive(Debug)]
+pub struct MemoryCap {
+    limit: MemoryLimit,
+    // The instance's cgroup, or `None` to fall back to `RLIMIT_AS`.
+    cgroup: Option<PathBuf>
+}
+impl MemoryCap {
+    /// Set up mriqc1's cgroup so that each instance of mriqc can get its own
+    /// cgroup, and return whether that worked.  Only leaf cgroups may contain
+    /// processes, so this moves mriqc1 into a new leaf named `mriqc1` before
+    /// enabling the memory controller for the cgroup it ran in, and moves it
+    /// back if that fails.  Call this once before creating any caps.
+    pub fn setup() -> bool {
+        CGROUP.get_or_init(|| setup_cgroup().ok()).is_some()
+    }
+
+    /// Create a cgroup named after mriqc1's process id and `name`, e.g.
+    /// `sub-01`, with `memory.max` set to `limit`.  Falls back to
+    /// `RLIMIT_AS` if cgroups are unavailable or [`MemoryCap::setup()`]
+    /// wasn't called, see [`cgroup_available()`].
+    pub fn new(limit: MemoryLimit, name: &str) -> Self {
+        let cgroup = delegated_cgroup().and_then(|parent| {
+            let cgroup = parent.join(format!("mriqc1-{}-{}", std::process::id(), name));
+            match create_cgroup(&cgroup, limit) {
+                Ok(()) => Some(cgroup),
+                Err(_) => {
+                    let _ = std::fs::remove_dir(&cgroup);
+                    None
+                }
+            }
+        });
+        Self { limit, cgroup }
+    }
+
+    /// Get the limit.
+    pub fn limit(&self) -> MemoryLimit {
+        self.limit
+    }
+
+    /// Get the path to the instance's cgroup, or `None` if the cap falls back
+    /// to `RLIMIT_AS`.
+    pub fn cgroup(&self) -> Option<&Path> {
+        self.cgroup.as_deref()
+    }
+
+    /// Make the process spawned by `command` join the cgroup, or set its
+    /// `RLIMIT_AS`, before it execs.  Its children inherit the limit.
+    pub fn apply(&self, command: &mut Command) {
+        match &self.cgroup {
+            Some(cgroup) => {
+                // Allocate the path now, since pre_exec() can't allocate.
+                let procs = cgroup.join("cgroup.procs");
+                let procs = std::ffi::CString::new(procs.as_os_str().as_bytes()).expect("cgroup path contains a nul byte");
+                unsafe {
+                    // Unsafe because the closure runs in the forked child
+                    // before exec, where only async-signal-safe functions may
+                    // be called.  Writing 0 to cgroup.procs moves the writing
+                    // process.
+                    command.pre_exec(move || {
+                        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                        if fd < 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                        let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+                        let error = std::io::Error::last_os_error();
+                        libc::close(fd);
+                        match written {
+                            1 => Ok(()),
+                            _ => Err(error)
+                        }
+                    });
+                }
+            },
+            None => {
+                let limit = libc::rlimit {
+                    rlim_cur: self.limit.bytes() as libc::rlim_t,
+                    rlim_max: self.limit.bytes() as libc::rlim_t
+                };
+                unsafe {
+                    // Unsafe for the same reason, setrlimit() is
+                    // async-signal-safe.
+                    command.pre_exec(move || match libc::setrlimit(libc::RLIMIT_AS, &limit) {
+                        0 => Ok(()),
+                        _ => Err(std::io::Error::last_os_error())
+                    });
+                }
+            }
+        }
+    }
+
+    /// Check whether the instance of mriqc ran out of memory.  With a cgroup
+    /// this checks whether the kernel's OOM killer stopped any of the
+    /// instance's processes.  With `RLIMIT_AS` allocations fail instead, so
+    /// this looks for the resulting errors in mriqc's captured `stderr`, such
+    /// as Python's `MemoryError`.
+    pub fn out_of_memory(&self, stderr: &[u8]) -> bool {
+        match &self.cgroup {
+            Some(cgroup) => std::fs::read_to_string(cgroup.join("memory.events"))
+                .map(|events| oom_kills(&events) > 0)
+                .unwrap_or(false),
+            None => {
+                let stderr = String::from_utf8_lossy(stderr);
+                ["MemoryError", "std::bad_alloc", "Cannot allocate memory", "Out of memory", "out of memory"].iter()
+                    .any(|message| stderr.contains(message))
+            }
+        }
+    }
+}
+impl Drop for MemoryCap {
+    fn drop(&mut self) {
+        if let Some(cgroup) = self.cgroup.take() {
+            // Waiting for the cgroup to empty blocks, so keep it off the
+            // async runtime's workers.
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => drop(runtime.spawn_blocking(move || remove_cgroup(&cgroup))),
+                Err(_) => remove_cgroup(&cgroup)
+            }
+        }
+    }
+}
+
+// Kill any stragglers in `cgroup`, on kernels that support cgroup.kill, and
+// wait up to a second for it to empty so that it can be removed.
+fn remove_cgroup(cgroup: &Path) {
+    let _ = std::fs::write(cgroup.join("cgroup.kill"), "1");
+    for _ in 0..100 {
+        match std::fs::remove_dir(cgroup) {
+            Err(error) if error.raw_os_error() == Some(libc::EBUSY) => std::thread::sleep(std::time::Duration::from_millis(10)),
+            _ => break
+        }
+    }
+}
+
+/// Check whether memory limits are enforced with cgroups, i.e.
+/// [`MemoryCap::setup()`] was called and succeeded.
+pub fn cgroup_available() -> bool {
+    delegated_cgroup().is_some()
+}
+
+// The cgroup under which we can create a cgroup for each instance of mriqc,
+// once MemoryCap::setup() has been called, or `None` if the memory controller
+// can't be delegated to us.
+static CGROUP: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+// Get the cgroup under which we can create a cgroup for each instance of
+// mriqc, or `None` if it isn't set up.
+fn delegated_cgroup() -> Option<&'static Path> {
+    CGROUP.get()?.as_deref()
+}
+
+// Enable the memory controller for the children of the cgroup we run in.
+// Only leaf cgroups may contain processes, so first move ourselves into a
+// leaf named `mriqc1`, and back out again if the controller can't be
+// enabled.  The files involved are in memory, so blocking on them is fine.
+fn setup_cgroup() -> std::io::Result<PathBuf> {
+    let unavailable = |what: &str| std::io::Error::new(std::io::ErrorKind::NotFound, what.to_string());
+    let mounts = std::fs::read_to_string("/proc/self/mounts")?;
+    let mount = cgroup2_mount(&mounts).ok_or_else(|| unavailable("cgroup2 isn't mounted"))?;
+    let ours = std::fs::read_to_string("/proc/self/cgroup")?;
+    let ours = own_cgroup(&ours).ok_or_else(|| unavailable("not in a cgroup2"))?;
+    let dir = mount.join(ours.trim_start_matches('/'));
+    let controllers = std::fs::read_to_string(dir.join("cgroup.controllers"))?;
+    if !controllers.split_whitespace().any(|controller| controller == "memory") {
+        return Err(unavailable("memory controller isn't available"));
+    }
+    let subtree = std::fs::read_to_string(dir.join("cgroup.subtree_control"))?;
+    if !subtree.split_whitespace().any(|controller| controller == "memory") {
+        let leaf = dir.join("mriqc1");
+        let created = match std::fs::create_dir(&leaf) {
+            Ok(()) => true,
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => false,
+            Err(error) => return Err(error)
+        };
+        let pid = std::process::id().to_string();
+        let enabled = std::fs::OpenOptions::new().write(true).open(leaf.join("cgroup.procs"))
+            .and_then(|mut procs| procs.write_all(pid.as_bytes()))
+            .and_then(|()| std::fs::write(dir.join("cgroup.subtree_control"), "+memory"));
+        if let Err(error) = enabled {
+            // E.g. EBUSY because other processes share the cgroup we ran in.
+            // Leave things as we found them.
+            let _ = std::fs::write(dir.join("cgroup.procs"), &pid);
+            if created {
+                let _ = std::fs::remove_dir(&leaf);
+            }
+            return Err(error);
+        }
+    }
+    Ok(dir)
+}
+
+// Create the cgroup at `path` with `memory.max` set to `limit`.
+fn create_cgroup(path: &Path, limit: MemoryLimit) -> std::io::Result<()> {
+    std::fs::create_dir(path)?;
+    std::fs::write(path.join("memory.max"), limit.bytes().to_string())
+}
+
+// Find where the cgroup2 filesystem is mounted in the contents of
+// `/proc/self/mounts`.
+fn cgroup2_mount(mounts: &str) -> Option<PathBuf> {
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount = fields.nth(1)?;
+        match fields.next()? {
+            // Spaces in the mount point are escaped as \040.
+            "cgroup2" => Some(PathBuf::from(mount.replace("\\040", " "))),
+            _ => None
+        }
+    })
+}
+
+// Find our cgroup2 path, e.g. `/user.slice/mriqc1.scope`, in the contents of
+// `/proc/self/cgroup`.
+fn own_cgroup(cgroup: &str) -> Option<&str> {
+    cgroup.lines().find_map(|line| line.strip_prefix("0::"))
+}
+
+// Count the processes killed by the OOM killer in the contents of a cgroup's
+// `memory.events`.
+fn oom_kills(events: &str) -> u64 {
+    events.lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_limit() {
+        assert_eq!("16G".parse::<MemoryLimit>().unwrap().bytes(), 16 << 30);
+        assert_eq!("512mb".parse::<MemoryLimit>().unwrap().bytes(), 512 << 20);
+        assert_eq!("1.5GiB".parse::<MemoryLimit>().unwrap().to_string(), "1536M");
+        assert_eq!("1000".parse::<MemoryLimit>().unwrap().to_string(), "1000");
+        assert!("16X".parse::<MemoryLimit>().is_err());
+        assert!("0G".parse::<MemoryLimit>().is_err());
+        assert!("G".parse::<MemoryLimit>().is_err());
+    }
+
+    #[test]
+    fn test_parse_cgroup_files() {
+        let mounts = "proc /proc proc rw 0 0\ncgroup2 /sys/fs/cgroup cgroup2 rw,nosuid 0 0\n";
+        assert_eq!(cgroup2_mount(mounts), Some(PathBuf::from("/sys/fs/cgroup")));
+        assert_eq!(cgroup2_mount("proc /proc proc rw 0 0\n"), None);
+        assert_eq!(own_cgroup("4:memory:/job\n0::/user.slice/mriqc1.scope\n"), Some("/user.slice/mriqc1.scope"));
+        assert_eq!(oom_kills("low 0\nhigh 0\nmax 12\noom 1\noom_kill 2\n"), 2);
+        assert_eq!(oom_kills("oom 0\n"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rlimit() {
+        let cap = MemoryCap { limit: "256M".parse().unwrap(), cgroup: None };
+        let mut command = Command::new("sh");
+        command.args(["-c", "ulimit -v"]);
+        cap.apply(&mut command);
+        let output = command.output().await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), (256 * 1024).to_string());
+        assert!(cap.out_of_memory(b"MemoryError: Unable to allocate array"));
+        assert!(!cap.out_of_memory(b"Traceback"));
+    }
+}
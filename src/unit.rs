@@ -0,0 +1,238 @@
+//! Units of work scheduled by mriqc1.  Each unit is processed by one instance
+//! of mriqc.
+
+use mriqc1::bids::{ParticipantLabel, SessionLabel};
+use mriqc1::mriqc::{check_outputs, ExpectedOutputs, MriqcError, Mriqc1Workspace};
+use std::path::PathBuf;
+
+/// One participant, or one session of one participant, to be processed by a
+/// single instance of mriqc.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkUnit {
+    /// Participant label.
+    pub participant: ParticipantLabel,
+    /// Session label, or `None` to process all of the participant's sessions.
+    pub session: Option<SessionLabel>,
+}
+impl WorkUnit {
+    /// Get the path to this unit's outputs relative to the output directory,
+    /// e.g. `sub-01` or `sub-01/ses-1`.
+    pub fn out_path(&self) -> PathBuf {
+        let path = self.participant.path();
+        match &self.session {
+            Some(session) => path.join(session.path()),
+            None => path
+        }
+    }
+}
+impl std::fmt::Display for WorkUnit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.session {
+            Some(session) => write!(f, "{} session {}", self.participant, session),
+            None => write!(f, "{}", self.participant)
+        }
+    }
+}
+
+/// Outcome of a unit of work that did not fail.
+#[derive(Debug)]
+pub enum Outcome {
+    /// mriqc ran to completion or was cancelled.
+    Finished,
+    /// mriqc was not run for this unit.
+    Skipped {
+        /// The unit that was skipped.
+        unit: WorkUnit,
+        /// Why the unit was skipped.
+        reason: String
+    }
+}
+
+/// A unit of work that needs mriqc to run, along with the outputs mriqc
+/// should produce for it.
+#[derive(Debug)]
+pub struct PendingUnit {
+    /// The unit of work.
+    pub unit: WorkUnit,
+    /// Outputs mriqc should produce, or `None` if we don't know which scans
+    /// mriqc will process.
+    pub outputs: Option<Vec<ExpectedOutputs>>,
+}
+
+/// Result of checking whether a unit of work needs mriqc to run.
+#[derive(Debug)]
+pub enum Checked {
+    /// mriqc needs to run for this unit.
+    Pending(PendingUnit),
+    /// The unit needs no further work.
+    Done(Outcome)
+}
+
+/// Divides units into batches of at most `size` units as they are checked,
+/// keeping units with the same session together, so that the first batches
+/// can start before every unit has been checked.
+#[derive(Debug)]
+pub struct Batcher {
+    size: usize,
+    // Batches still being filled, in the order their first unit arrived.
+    open: Vec<Batch>,
+}
+impl Batcher {
+    /// Create a batcher for batches of at most `size` units.
+    pub fn new(size: usize) -> Self {
+        Self { size: size.max(1), open: Vec::new() }
+    }
+
+    /// Add `unit` to the batch for its session, returning the batch if it is
+    /// now full.
+    pub fn push(&mut self, unit: PendingUnit) -> Option<Batch> {
+        let index = match self.open.iter().position(|batch| batch.0[0].unit.session == unit.unit.session) {
+            Some(index) => {
+                self.open[index].0.push(unit);
+                index
+            },
+            None => {
+                self.open.push(Batch(vec![unit]));
+                self.open.len() - 1
+            }
+        };
+        match self.open[index].0.len() >= self.size {
+            true => Some(self.open.remove(index)),
+            false => None
+        }
+    }
+
+    /// Take the batches that aren't full yet, once there are no more units.
+    pub fn finish(&mut self) -> Vec<Batch> {
+        std::mem::take(&mut self.open)
+    }
+}
+
+/// Units of work processed together by one instance of mriqc.  All the units
+/// in a batch are for the same session, or for all sessions.
+#[derive(Debug)]
+pub struct Batch(Vec<PendingUnit>);
+impl Batch {
+
+    /// Get the participants in this batch.
+    pub fn participants(&self) -> Vec<&ParticipantLabel> {
+        self.0.iter().map(|pending| &pending.unit.participant).collect()
+    }
+
+    /// Get the units in this batch.
+    pub fn units(&self) -> impl Iterator<Item = &WorkUnit> {
+        self.0.iter().map(|pending| &pending.unit)
+    }
+
+    /// Get the session shared by every unit in this batch, if any.
+    pub fn session(&self) -> Option<&SessionLabel> {
+        self.0.first().and_then(|pending| pending.unit.session.as_ref())
+    }
+
+    /// Outcome of each unit when mriqc finished or was cancelled.
+    pub fn finished(self) -> Vec<Result<Outcome, MriqcError>> {
+        self.0.iter().map(|_| Ok(Outcome::Finished)).collect()
+    }
+
+    /// Outcome of each unit when we were interrupted before mriqc started.
+    pub fn interrupted(self) -> Vec<Result<Outcome, MriqcError>> {
+        self.0.into_iter().map(|pending| Ok(Outcome::Skipped {
+            unit: pending.unit,
+            reason: "interrupted before mriqc started".into()
+        })).collect()
+    }
+
+    /// Outcome of each unit when mriqc failed with `error`.  When several
+    /// participants share an instance of mriqc, those whose outputs are all
+    /// present are considered finished.  The first unit that isn't finished
+    /// gets the error, and the rest refer to it.
+    pub async fn failed(self, error: MriqcError) -> Vec<Result<Outcome, MriqcError>> {
+        let batched = self.0.len() > 1;
+        let timed_out = error.is_timeout();
+        let mut error = Some(error);
+        let mut reported = String::new();
+        let mut outcomes = Vec::with_capacity(self.0.len());
+        for pending in self.0 {
+            let complete = match (batched, &pending.outputs) {
+                (true, Some(outputs)) => check_outputs(outputs).await.is_complete(),
+                _ => false
+            };
+            if complete {
+                outcomes.push(Ok(Outcome::Finished));
+                continue;
+            }
+            match error.take() {
+                Some(error) => {
+                    reported = pending.unit.to_string();
+                    outcomes.push(Err(error));
+                },
+                None => outcomes.push(Err(MriqcError::BatchFailed {
+                    participant: pending.unit.to_string(),
+                    reported: reported.clone(),
+                    timed_out
+                }))
+            }
+        }
+        // Every unit was finished, so mriqc must have failed after processing
+        // them.  Don't lose the error.
+        if let Some(error) = error {
+            outcomes[0] = Err(error);
+        }
+        outcomes
+    }
+}
+impl std::fmt::Display for Batch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let participants: Vec<&str> = self.participants().into_iter().map(ParticipantLabel::as_str).collect();
+        write!(f, "{}", participants.join(", "))?;
+        if let Some(session) = self.session() {
+            write!(f, " session {}", session)?;
+        }
+        Ok(())
+    }
+}
+
+/// A batch of work after its preparation.
+pub enum Prepared {
+    /// The batch's workspace is ready for mriqc.  Boxed because the
+    /// workspace is much larger than the other variant.
+    Ready(Batch, Box<Mriqc1Workspace>),
+    /// The batch needs no further work, e.g. because preparing it failed.
+    Done(Vec<Result<Outcome, MriqcError>>)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending(participant: &str, session: Option<&str>) -> PendingUnit {
+        PendingUnit {
+            unit: WorkUnit {
+                participant: participant.parse().unwrap(),
+                session: session.map(|session| session.parse().unwrap())
+            },
+            outputs: None
+        }
+    }
+
+    // Batch `units` and return the batches in the order they are done.
+    fn divide(units: Vec<PendingUnit>, size: usize) -> Vec<String> {
+        let mut batcher = Batcher::new(size);
+        let mut batches: Vec<Batch> = units.into_iter().filter_map(|unit| batcher.push(unit)).collect();
+        batches.extend(batcher.finish());
+        batches.iter().map(Batch::to_string).collect()
+    }
+
+    #[test]
+    fn test_batcher() {
+        let units = vec![
+            pending("01", Some("1")), pending("01", Some("2")),
+            pending("02", Some("2")), pending("03", Some("1")),
+            pending("04", Some("1"))
+        ];
+        assert_eq!(divide(units, 2), vec!["01, 02 session 2", "01, 03 session 1", "04 session 1"]);
+        let units = vec![pending("01", None), pending("02", None), pending("03", None)];
+        assert_eq!(divide(units, 5), vec!["01, 02, 03"]);
+        assert_eq!(divide(vec![pending("01", None)], 0), vec!["01"]);
+    }
+}
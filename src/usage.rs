@@ -0,0 +1,366 @@
+//! Measure the memory, CPU and I/O used by an instance of mriqc, including
+//! all of its descendants such as nipype's workers and the tools they run.
+//!
+//! [`UsageMonitor::sample()`] walks `/proc` to find the instance's process
+//! tree and totals the resident memory from `/proc/<pid>/stat`, the CPU time
+//! from the same file, and the bytes read from and written to storage from
+//! `/proc/<pid>/io`.  CPU and I/O are turned into rates by comparing each
+//! process with the previous sample, so processes that start and exit between
+//! samples are missed.
+
+use crate::logs::LOGS_DIR;
+use crate::proc_stat::Stat;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+/// Name of the file within `<out_dir>/logs/mriqc1` where [`UsageLog`]
+/// records each participant's peak usage.
+pub const USAGE_FILE: &str = "usage.tsv";
+
+/// Resources used by a process tree at one moment, or the peak of each over a
+/// period of time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    /// Number of processes.
+    pub processes: usize,
+    /// Resident memory in bytes.
+    pub rss: u64,
+    /// CPU use as a percentage of one CPU, e.g. 400 for four busy CPUs.
+    pub cpu: f64,
+    /// Bytes read from and written to storage per second.
+    pub io: f64,
+}
+impl Usage {
+    // Take the larger of each field.
+    fn max(self, other: Usage) -> Usage {
+        Usage {
+            processes: self.processes.max(other.processes),
+            rss: self.rss.max(other.rss),
+            cpu: self.cpu.max(other.cpu),
+            io: self.io.max(other.io)
+        }
+    }
+}
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} RSS, {:.0}% CPU, {}/s I/O", human_bytes(self.rss as f64), self.cpu, human_bytes(self.io))
+    }
+}
+
+/// Samples the resources used by the process tree rooted at one process, see
+/// the [module documentation](self).  Processes in the root's process group
+/// are included even if they were orphaned and adopted by another process.
+#[derive(Debug)]
+pub struct UsageMonitor {
+    root: u32,
+    // When monitoring started.
+    started: Instant,
+    // When the previous sample was taken.
+    sampled: Option<Instant>,
+    // CPU ticks and I/O bytes of each process at the previous sample, by
+    // process id and start time so that reused process ids aren't confused.
+    previous: HashMap<(u32, u64), (u64, u64)>,
+    current: Usage,
+    peak: Usage,
+    // Total CPU ticks and I/O bytes seen so far.
+    cpu_ticks: u64,
+    io_bytes: u64,
+}
+impl UsageMonitor {
+    /// Monitor the process `root` and its descendants.
+    pub fn new(root: u32) -> Self {
+        Self {
+            root,
+            started: Instant::now(),
+            sampled: None,
+            previous: HashMap::new(),
+            current: Usage::default(),
+            peak: Usage::default(),
+            cpu_ticks: 0,
+            io_bytes: 0
+        }
+    }
+
+    /// Sample the process tree now and return its current usage.  The first
+    /// sample has no CPU or I/O rates since there is nothing to compare it
+    /// with.  Walking `/proc` reads a few files for every process on the
+    /// system, so it is done on a thread where blocking is allowed.
+    pub async fn sample(&mut self) -> Usage {
+        let root = self.root;
+        let tree = tokio::task::spawn_blocking(move || process_tree(root)).await.unwrap_or_default();
+        let now = Instant::now();
+        let mut usage = Usage { processes: tree.len(), ..Default::default() };
+        let mut ticks = 0;
+        let mut io = 0;
+        let mut previous = HashMap::with_capacity(tree.len());
+        for (stat, io_bytes) in tree {
+            let key = (stat.pid, stat.start_ticks);
+            // Processes that weren't in the previous sample started since.
+            let (ticks_before, io_before) = match self.sampled {
+                Some(_) => self.previous.get(&key).copied().unwrap_or((0, 0)),
+                None => (stat.cpu_ticks, io_bytes)
+            };
+            ticks += stat.cpu_ticks.saturating_sub(ticks_before);
+            io += io_bytes.saturating_sub(io_before);
+            usage.rss += stat.rss_pages * page_size();
+            previous.insert(key, (stat.cpu_ticks, io_bytes));
+        }
+        if let Some(sampled) = self.sampled {
+            let elapsed = now.duration_since(sampled).as_secs_f64();
+            if elapsed > 0.0 {
+                usage.cpu = ticks as f64 / clock_ticks() as f64 / elapsed * 100.0;
+                usage.io = io as f64 / elapsed;
+            }
+        }
+        self.cpu_ticks += ticks;
+        self.io_bytes += io;
+        self.previous = previous;
+        self.sampled = Some(now);
+        self.current = usage;
+        self.peak = self.peak.max(usage);
+        usage
+    }
+
+    /// Drive `future` to completion, sampling every `interval` and calling
+    /// `sampled` with the monitor after each sample.
+    pub async fn sample_while<F: Future, S: FnMut(&UsageMonitor)>(&mut self, future: F, interval: Duration, mut sampled: S) -> F::Output {
+        tokio::pin!(future);
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                output = &mut future => return output,
+                _ = ticker.tick() => {
+                    self.sample().await;
+                    sampled(self);
+                }
+            }
+        }
+    }
+
+    /// Get the usage at the last sample.
+    pub fn current(&self) -> Usage {
+        self.current
+    }
+
+    /// Get the peak of each kind of usage over all samples.
+    pub fn peak(&self) -> Usage {
+        self.peak
+    }
+
+    /// Summarize the usage since monitoring started.
+    pub fn summary(&self) -> UsageSummary {
+        UsageSummary {
+            elapsed: self.started.elapsed(),
+            peak: self.peak,
+            cpu_time: Duration::from_secs_f64(self.cpu_ticks as f64 / clock_ticks() as f64),
+            io_bytes: self.io_bytes
+        }
+    }
+}
+
+/// Usage of a process tree over its lifetime, see [`UsageMonitor::summary()`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct UsageSummary {
+    /// How long the process tree was monitored.
+    pub elapsed: Duration,
+    /// Peak of each kind of usage.
+    pub peak: Usage,
+    /// Total CPU time used.
+    pub cpu_time: Duration,
+    /// Total bytes read from and written to storage.
+    pub io_bytes: u64,
+}
+
+/// Records each participant's [`UsageSummary`] as a row of
+/// `<out_dir>/logs/mriqc1/usage.tsv`, which is shared by every run of mriqc1
+/// writing to the same output directory.  Participants processed by the same
+/// instance of mriqc share its usage, so their rows repeat the same figures
+/// and name the whole batch in the `batch` column.
+#[derive(Debug)]
+pub struct UsageLog {
+    path: PathBuf,
+    // Serializes appends so that the header is only written once.
+    lock: tokio::sync::Mutex<()>,
+}
+impl UsageLog {
+    /// Columns of the file, which is created with this header.
+    pub const HEADER: &'static str = "participant_id\tsession_id\tbatch\tstatus\telapsed_s\tpeak_processes\tpeak_rss_bytes\tpeak_cpu_percent\tcpu_s\tpeak_io_bytes_per_s\tio_bytes";
+
+    /// Record usage in the output directory `out_dir`.
+    pub fn new(out_dir: &Path) -> Self {
+        Self {
+            path: out_dir.join("logs").join(LOGS_DIR).join(USAGE_FILE),
+            lock: tokio::sync::Mutex::new(())
+        }
+    }
+
+    /// Get the path to the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append a row for `participant`, e.g. `sub-01`, and `session`, e.g.
+    /// `ses-1` or `None` for all sessions.  `batch` lists the participants
+    /// that `summary` was measured over, e.g. `sub-01,sub-02`.  `status` says
+    /// how mriqc finished for this participant, e.g. `finished` or `failed`.
+    pub async fn append(&self, participant: &str, session: Option<&str>, batch: &str, status: &str, summary: &UsageSummary) -> std::io::Result<()> {
+        let _lock = self.lock.lock().await;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await?;
+        let mut row = String::new();
+        if file.metadata().await?.len() == 0 {
+            row.push_str(Self::HEADER);
+            row.push('\n');
+        }
+        row.push_str(&format!("{}\t{}\t{}\t{}\t{:.0}\t{}\t{}\t{:.0}\t{:.0}\t{:.0}\t{}\n",
+            participant,
+            session.unwrap_or("n/a"),
+            batch,
+            status,
+            summary.elapsed.as_secs_f64(),
+            summary.peak.processes,
+            summary.peak.rss,
+            summary.peak.cpu,
+            summary.cpu_time.as_secs_f64(),
+            summary.peak.io,
+            summary.io_bytes
+        ));
+        file.write_all(row.as_bytes()).await?;
+        file.flush().await
+    }
+}
+
+// Find the processes in the tree rooted at `root`, plus any others in its
+// process group, along with the bytes each read and wrote.
+fn process_tree(root: u32) -> Vec<(Stat, u64)> {
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new()
+    };
+    let stats: Vec<Stat> = entries.filter_map(|entry| {
+        let name = entry.ok()?.file_name();
+        let pid: u32 = name.to_str()?.parse().ok()?;
+        Stat::read(pid).ok()
+    }).collect();
+    select_tree(root, stats).into_iter().map(|stat| (stat, read_io(stat.pid).unwrap_or(0))).collect()
+}
+
+// Select the processes descended from `root`, or in its process group, out of
+// all the processes in `stats`.
+fn select_tree(root: u32, stats: Vec<Stat>) -> Vec<Stat> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for stat in &stats {
+        children.entry(stat.ppid).or_default().push(stat.pid);
+    }
+    let mut tree = HashSet::new();
+    let mut pending = vec![root];
+    while let Some(pid) = pending.pop() {
+        if tree.insert(pid) {
+            pending.extend(children.get(&pid).into_iter().flatten());
+        }
+    }
+    stats.into_iter().filter(|stat| tree.contains(&stat.pid) || stat.pgrp == root).collect()
+}
+
+// Read the bytes a process read from and wrote to storage from
+// /proc/<pid>/io, which is only readable by the process's owner.
+fn read_io(pid: u32) -> Option<u64> {
+    parse_io(&std::fs::read_to_string(format!("/proc/{}/io", pid)).ok()?)
+}
+
+// Parse `read_bytes` plus `write_bytes` out of the contents of
+// /proc/<pid>/io.
+fn parse_io(io: &str) -> Option<u64> {
+    let field = |name: &str| io.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    Some(field("read_bytes")? + field("write_bytes")?)
+}
+
+// Clock ticks per second, the unit of CPU times in /proc/<pid>/stat.
+fn clock_ticks() -> u64 {
+    // Safe because sysconf() has no side effects.
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        _ => 100
+    }
+}
+
+// Size of a memory page in bytes, the unit of RSS in /proc/<pid>/stat.
+fn page_size() -> u64 {
+    // Safe because sysconf() has no side effects.
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as u64,
+        _ => 4096
+    }
+}
+
+// Format a number of bytes with a binary suffix, e.g. 1.5G.
+fn human_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    for unit in ["", "K", "M", "G"] {
+        if value < 1024.0 {
+            return match unit {
+                "" => format!("{:.0}B", value),
+                unit => format!("{:.1}{}", value, unit)
+            };
+        }
+        value /= 1024.0;
+    }
+    format!("{:.1}T", value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc() {
+        let io = "rchar: 100\nwchar: 200\nsyscr: 1\nsyscw: 2\nread_bytes: 4096\nwrite_bytes: 8192\ncancelled_write_bytes: 0\n";
+        assert_eq!(parse_io(io), Some(12288));
+        assert_eq!(human_bytes(512.0), "512B");
+        assert_eq!(human_bytes(1.5 * (1u64 << 30) as f64), "1.5G");
+    }
+
+    #[test]
+    fn test_select_tree() {
+        let stat = |pid, ppid, pgrp| Stat { pid, ppid, pgrp, cpu_ticks: 0, start_ticks: 0, rss_pages: 0 };
+        // 10 forks 11, which forks 12.  13 is an orphan in 10's group and 20
+        // is unrelated.
+        let stats = vec![stat(1, 0, 1), stat(10, 1, 10), stat(11, 10, 10), stat(12, 11, 12), stat(13, 1, 10), stat(20, 1, 20)];
+        let mut pids: Vec<u32> = select_tree(10, stats).iter().map(|stat| stat.pid).collect();
+        pids.sort_unstable();
+        assert_eq!(pids, vec![10, 11, 12, 13]);
+    }
+
+    #[tokio::test]
+    async fn test_monitor() {
+        let mut child = tokio::process::Command::new("sh")
+            .args(["-c", "sleep 10 & sleep 10 & wait"])
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let mut monitor = UsageMonitor::new(child.id().unwrap());
+        let mut samples = 0;
+        monitor.sample_while(tokio::time::sleep(Duration::from_millis(250)), Duration::from_millis(100), |_| samples += 1).await;
+        assert!(samples >= 2);
+        assert_eq!(monitor.current().processes, 3);
+        assert!(monitor.peak().rss > 0);
+        child.kill().await.unwrap();
+
+        let out_dir = tempfile::tempdir().unwrap();
+        let log = UsageLog::new(out_dir.path());
+        log.append("sub-01", None, "sub-01", "finished", &monitor.summary()).await.unwrap();
+        log.append("sub-02", Some("ses-1"), "sub-02,sub-03", "failed", &monitor.summary()).await.unwrap();
+        let contents = std::fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], UsageLog::HEADER);
+        assert!(lines[2].starts_with("sub-02\tses-1\tsub-02,sub-03\tfailed\t"));
+    }
+}
@@ -0,0 +1,316 @@
+//! Tools for finding temporary directories left behind in the working
+//! directory.
+//!
+//! Each instance of mriqc gets its own temporary directory, which is normally
+//! removed when the instance's resources are dropped.  If mriqc1 itself is
+//! killed, e.g. with SIGKILL by the out-of-memory killer or a scheduler's
+//! walltime limit, then destructors never run and the directory is left
+//! behind.  To recover, every temporary directory is tagged with an
+//! [`OWNER_FILE`] recording which process created it.  [`find_stale()`] finds
+//! directories whose owner is gone so they can be removed.
+//!
+//! Alternatively, each instance of mriqc can reuse a stable directory such as
+//! `mriqc1-sub-01` across attempts so that nipype's cache survives retries.
+//! These are never considered stale.  Instead a [`LockedDir`] guarantees that
+//! only one instance of mriqc uses the directory at a time.
+
+use crate::proc_stat::Stat;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Name of the marker file recording the owner of a temporary directory.
+pub const OWNER_FILE: &str = ".mriqc1-owner";
+
+/// Name of the lock file in a reused directory, see [`LockedDir`].
+pub const LOCK_FILE: &str = ".mriqc1-lock";
+
+/// Custom error type.
+#[derive(Error, Debug)]
+pub enum WorkDirError {
+    /// Couldn't find out about the current process.
+    #[error("Couldn't identify the current process")]
+    Identify {
+        source: std::io::Error
+    },
+    /// Couldn't write the marker file.
+    #[error("Couldn't write owner file: {}", path.to_string_lossy())]
+    Write {
+        path: PathBuf,
+        source: std::io::Error
+    },
+    /// Couldn't read the working directory.
+    #[error("Couldn't read working directory: {}", path.to_string_lossy())]
+    ReadDir {
+        path: PathBuf,
+        source: std::io::Error
+    },
+    /// Couldn't remove a stale directory.
+    #[error("Couldn't remove stale directory: {}", path.to_string_lossy())]
+    Remove {
+        path: PathBuf,
+        source: std::io::Error
+    },
+    /// Couldn't create or lock a reused directory.
+    #[error("Couldn't lock directory: {}", path.to_string_lossy())]
+    Lock {
+        path: PathBuf,
+        source: std::io::Error
+    },
+    /// A reused directory is already locked by another instance of mriqc.
+    #[error("Directory is in use by another instance of mriqc: {}", path.to_string_lossy())]
+    Locked {
+        path: PathBuf
+    },
+}
+
+/// The process that owns a temporary directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    /// Process id.
+    pub pid: u32,
+    /// Hostname of the machine the process ran on.
+    pub host: String,
+    /// Identifies the boot of the machine, see
+    /// `/proc/sys/kernel/random/boot_id`.  Process ids and start times are
+    /// only meaningful within one boot.
+    pub boot_id: String,
+    /// Start time of the process in clock ticks since boot, see `starttime` in
+    /// `proc(5)`.  Distinguishes the owner from a later process that reused
+    /// its pid.
+    pub start_ticks: u64,
+    /// When the process started, in seconds since the Unix epoch.
+    pub started: u64,
+}
+impl Owner {
+    /// Identify the current process.
+    pub fn current() -> Result<Self, WorkDirError> {
+        let identify_err = |source| WorkDirError::Identify { source };
+        let pid = std::process::id();
+        Ok(Self {
+            pid,
+            host: hostname().map_err(identify_err)?,
+            boot_id: boot_id().map_err(identify_err)?,
+            start_ticks: Stat::read(pid).map_err(identify_err)?.start_ticks,
+            started: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+        })
+    }
+
+    /// Check if the owner is still running.  Returns `None` if the owner ran
+    /// on a different host, in which case we can't tell.
+    pub fn is_alive(&self) -> Option<bool> {
+        if hostname().ok()? != self.host {
+            return None;
+        }
+        // After a reboot none of the previous boot's processes are alive.
+        if boot_id().ok()? != self.boot_id {
+            return Some(false);
+        }
+        // Is there a process with this pid that started at the same time?
+        Some(Stat::read(self.pid).map(|stat| stat.start_ticks == self.start_ticks).unwrap_or(false))
+    }
+}
+impl std::fmt::Display for Owner {
+    /// Format as the contents of an [`OWNER_FILE`], one `key=value` per line.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "pid={}", self.pid)?;
+        writeln!(f, "host={}", self.host)?;
+        writeln!(f, "boot_id={}", self.boot_id)?;
+        writeln!(f, "start_ticks={}", self.start_ticks)?;
+        writeln!(f, "started={}", self.started)
+    }
+}
+impl std::str::FromStr for Owner {
+    type Err = String;
+    /// Parse the contents of an [`OWNER_FILE`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let get = |key: &str| s.lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| value.to_string())
+            .ok_or_else(|| format!("missing {}", key));
+        let number = |key: &str| get(key)?.parse::<u64>().map_err(|e| format!("invalid {}: {}", key, e));
+        Ok(Self {
+            pid: number("pid")? as u32,
+            host: get("host")?,
+            boot_id: get("boot_id")?,
+            start_ticks: number("start_ticks")?,
+            started: number("started")?
+        })
+    }
+}
+
+/// Tag the temporary directory `dir` as owned by the current process.
+pub async fn write_owner<P: AsRef<Path>>(dir: P) -> Result<(), WorkDirError> {
+    let path = dir.as_ref().join(OWNER_FILE);
+    let owner = Owner::current()?;
+    tokio::fs::write(&path, owner.to_string()).await.map_err(|source| WorkDirError::Write { path, source })
+}
+
+/// Read the owner of the temporary directory `dir`.  Returns `None` if the
+/// directory has no owner file or it can't be read.
+pub async fn read_owner<P: AsRef<Path>>(dir: P) -> Option<Owner> {
+    tokio::fs::read_to_string(dir.as_ref().join(OWNER_FILE)).await.ok()?.parse().ok()
+}
+
+/// A temporary directory in the working directory, and its owner.
+#[derive(Debug, Clone)]
+pub struct OwnedDir {
+    /// Path to the temporary directory.
+    pub path: PathBuf,
+    /// The process that created it.
+    pub owner: Owner,
+}
+impl OwnedDir {
+    /// Remove the directory and all of its contents.
+    pub async fn remove(&self) -> Result<(), WorkDirError> {
+        tokio::fs::remove_dir_all(&self.path).await.map_err(|source| WorkDirError::Remove {
+            path: self.path.clone(),
+            source
+        })
+    }
+}
+
+/// Temporary directories found in the working directory, see
+/// [`find_stale()`].
+#[derive(Debug, Clone, Default)]
+pub struct WorkDirScan {
+    /// Directories whose owner is gone.
+    pub stale: Vec<OwnedDir>,
+    /// Directories whose owner is still running.
+    pub in_use: Vec<OwnedDir>,
+    /// Directories owned by a process on another host.
+    pub other_host: Vec<OwnedDir>,
+}
+
+/// Find the temporary directories in `work_dir` that have an owner file,
+/// sorted by whether their owner is gone.
+pub async fn find_stale<P: AsRef<Path>>(work_dir: P) -> Result<WorkDirScan, WorkDirError> {
+    let work_dir = work_dir.as_ref();
+    let read_dir_err = |source| WorkDirError::ReadDir {
+        path: work_dir.into(),
+        source
+    };
+    let mut scan = WorkDirScan::default();
+    let mut entries = tokio::fs::read_dir(work_dir).await.map_err(read_dir_err)?;
+    while let Some(entry) = entries.next_entry().await.map_err(read_dir_err)? {
+        let path = entry.path();
+        let owner = match read_owner(&path).await {
+            Some(owner) => owner,
+            None => continue
+        };
+        let alive = owner.is_alive();
+        let dir = OwnedDir { path, owner };
+        match alive {
+            Some(false) => scan.stale.push(dir),
+            Some(true) => scan.in_use.push(dir),
+            None => scan.other_host.push(dir)
+        }
+    }
+    for dirs in [&mut scan.stale, &mut scan.in_use, &mut scan.other_host] {
+        dirs.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+    Ok(scan)
+}
+
+/// A directory that is reused across runs, locked for exclusive use by this
+/// process.  The lock is an advisory `flock(2)` on the directory's
+/// [`LOCK_FILE`], which the kernel releases when the lock is dropped or the
+/// process dies, so a killed instance of mriqc1 never leaves a directory
+/// locked.  Unlike a temporary directory, the directory is not removed when
+/// the lock is dropped.
+#[derive(Debug)]
+pub struct LockedDir {
+    // Path to the directory.
+    path: PathBuf,
+    // Open lock file holding the lock.
+    _lock: std::fs::File,
+}
+impl LockedDir {
+    /// Create the directory at `path` if it doesn't exist yet and lock it.
+    /// Returns [`WorkDirError::Locked`] without waiting if another process
+    /// holds the lock.
+    pub async fn new<P: Into<PathBuf>>(path: P) -> Result<Self, WorkDirError> {
+        let path = path.into();
+        let lock_err = |source| WorkDirError::Lock {
+            path: path.clone(),
+            source
+        };
+        tokio::fs::create_dir_all(&path).await.map_err(lock_err)?;
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.join(LOCK_FILE))
+            .map_err(lock_err)?;
+        // Safe because the file descriptor stays open for the lifetime of
+        // `lock`.
+        let res = unsafe { libc::flock(std::os::unix::io::AsRawFd::as_raw_fd(&lock), libc::LOCK_EX | libc::LOCK_NB) };
+        if res != 0 {
+            let source = std::io::Error::last_os_error();
+            return Err(match source.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => WorkDirError::Locked { path },
+                _ => lock_err(source)
+            });
+        }
+        Ok(Self { path, _lock: lock })
+    }
+
+    /// Get the path to the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove the directory and all of its contents, releasing the lock.
+    pub async fn remove(self) -> Result<(), WorkDirError> {
+        tokio::fs::remove_dir_all(&self.path).await.map_err(|source| WorkDirError::Remove {
+            path: self.path.clone(),
+            source
+        })
+    }
+}
+
+// Get the hostname of this machine.
+fn hostname() -> std::io::Result<String> {
+    let mut buf = [0u8; 256];
+    // Safe because the buffer is valid for its whole length.
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+// Get the identifier of the current boot.
+fn boot_id() -> std::io::Result<String> {
+    Ok(std::fs::read_to_string("/proc/sys/kernel/random/boot_id")?.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owner() {
+        let owner = Owner::current().unwrap();
+        assert_eq!(owner.to_string().parse::<Owner>().unwrap(), owner);
+        assert_eq!(owner.is_alive(), Some(true));
+        let reused = Owner { start_ticks: owner.start_ticks + 1, ..owner.clone() };
+        assert_eq!(reused.is_alive(), Some(false));
+        let remote = Owner { host: format!("not-{}", owner.host), ..owner };
+        assert_eq!(remote.is_alive(), None);
+    }
+
+    #[tokio::test]
+    async fn test_locked_dir() {
+        let work_dir = tempfile::tempdir().unwrap();
+        let path = work_dir.path().join("mriqc1-sub-01");
+        let locked = LockedDir::new(&path).await.unwrap();
+        assert!(matches!(LockedDir::new(&path).await, Err(WorkDirError::Locked { .. })));
+        drop(locked);
+        assert!(path.is_dir());
+        LockedDir::new(&path).await.unwrap().remove().await.unwrap();
+        assert!(!path.exists());
+    }
+}